@@ -1,44 +1,714 @@
-use std::path::PathBuf;
+use std::{env, fmt::Display, fs, iter, path::PathBuf, process};
 
-use clap::{Parser, Subcommand};
-use kidex_common::util::{get_index, regenerate_index, reload_config, shutdown_server};
+use clap::{CommandFactory, Parser, Subcommand, ValueHint};
+use globber::Pattern;
+use kidex_common::{
+    util::{
+        add_ignore_pattern, check_version, clean_orphans, dump_index, find_by_name,
+        force_reload_config, force_rewatch, fuzzy_search, get_at_depth, get_descriptor_count,
+        get_empty_dirs, get_index, get_index_as_tree, get_index_count, get_index_for_descriptor,
+        get_largest_files, get_orphaned_paths, get_parent_chain, get_pid, get_recently_created,
+        get_recently_deleted, get_roots, get_stats, get_symlinks, get_watch_dir_for_path,
+        graceful_shutdown, health_check, list_extensions, list_ignore_patterns, load_index,
+        move_path, prune_stale, query_index_exact, regenerate_index_with_progress, reload_config,
+        remove_ignore_pattern, set_watch_mask, shutdown_server, simulate_create, simulate_delete,
+        subscribe, update_ignored_for, validate_watchers, Error,
+    },
+    TreeNode,
+};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
 
 #[derive(Parser)]
 struct Opts {
+    /// Enable low-level debugging subcommands
+    #[arg(long, global = true)]
+    debug: bool,
     #[command(subcommand)]
     subcommand: Command,
 }
 
 #[derive(Subcommand)]
 enum Command {
-    Shutdown,
-    ReloadConfig,
-    RegenerateIndex,
-    GetIndex { path: Option<PathBuf> },
+    Shutdown {
+        /// Let running operations finish before quitting
+        #[arg(long)]
+        graceful: bool,
+    },
+    ReloadConfig {
+        /// Reload right away instead of waiting for the config to settle
+        #[arg(short, long)]
+        force: bool,
+    },
+    RegenerateIndex {
+        /// Print the progress of the reindex
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Print the indexed paths nested exactly this many levels deep, 1 being direct children
+    AtDepth {
+        depth: usize,
+        /// Count the levels from this directory instead of the WatchDir roots
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        root: Option<PathBuf>,
+    },
+    /// Print every indexed path with exactly this file name
+    ByName {
+        filename: String,
+    },
+    /// Print the indexed symlinks with their targets, marking the broken ones
+    Symlinks {
+        /// Only consider symlinks under this directory
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        root: Option<PathBuf>,
+    },
+    /// Print the watched directories that have no indexed children
+    EmptyDirs {
+        /// Only consider directories under this directory
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        root: Option<PathBuf>,
+    },
+    /// Print the largest indexed files, largest first
+    Largest {
+        #[arg(short, long, default_value_t = 10)]
+        limit: usize,
+        /// Only consider files under this directory
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        root: Option<PathBuf>,
+    },
+    /// Print the paths with file names most similar to the query, best matches first
+    Fuzzy {
+        query: String,
+        #[arg(short, long, default_value_t = 10)]
+        limit: usize,
+    },
+    /// Print every file extension in the index with the amount of files having it
+    Extensions {
+        /// Only count files under this directory
+        #[arg(long, value_hint = ValueHint::DirPath)]
+        root: Option<PathBuf>,
+    },
+    /// Print the index entry of an exact path, failing if it is not indexed
+    Exact {
+        path: PathBuf,
+    },
+    /// Print the amount of indexed files and directories
+    Count {
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: Option<PathBuf>,
+    },
+    GetIndex {
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: Option<PathBuf>,
+        /// Indent the JSON output
+        #[arg(long, conflicts_with = "json_compact")]
+        json_pretty: bool,
+        /// Print the JSON output on a single line, the default
+        #[arg(long, conflicts_with = "json_pretty")]
+        json_compact: bool,
+    },
+    /// Print the index as a tree
+    Tree {
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: Option<PathBuf>,
+    },
+    GetPid,
+    /// Print the amount of inotify watchers in use and the system limit
+    WatchCount,
+    /// Print the indexed directory of a raw inotify watch descriptor, requires --debug
+    DescriptorInfo {
+        id: u64,
+    },
+    Stats,
+    AddIgnorePattern {
+        pattern: String,
+    },
+    RemoveIgnorePattern {
+        pattern: String,
+    },
+    /// Print the ignore patterns from the config and the ones added at runtime
+    ListIgnorePatterns,
+    /// Check if an ignore pattern matches a path without needing the daemon, exiting with 1 if not
+    TestIgnore {
+        pattern: String,
+        path: PathBuf,
+        /// Print the parsed form of the pattern
+        #[arg(short, long)]
+        verbose: bool,
+    },
+    /// Print the configuration of the WatchDir responsible for a path
+    WhichWatchDir {
+        path: PathBuf,
+    },
+    /// Print the directories from the WatchDir root down to the parent of an indexed path
+    ParentChain {
+        path: PathBuf,
+    },
+    /// Print the indexed root directories, one per line
+    Roots,
+    /// Change the inotify events watched for a directory until the next config reload
+    SetWatchMask {
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: PathBuf,
+        /// Event names like CREATE, DELETE or CLOSE_WRITE
+        #[arg(required = true)]
+        events: Vec<String>,
+    },
+    /// Replace the ignore patterns of a watched directory until the next config reload
+    UpdateIgnored {
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: PathBuf,
+        /// Glob patterns, none to clear the directory's own patterns
+        patterns: Vec<String>,
+    },
+    /// Index a path and notify subscribers as if it had been created, for testing integrations
+    SimulateCreate {
+        path: PathBuf,
+    },
+    /// Un-index a path and notify subscribers as if it had been deleted, for testing integrations
+    SimulateDelete {
+        path: PathBuf,
+    },
+    /// Re-register the watcher of an indexed directory, e.g. after its device was remounted
+    Rewatch {
+        #[arg(value_hint = ValueHint::DirPath)]
+        path: PathBuf,
+    },
+    /// Update the index for a rename it missed
+    MovePath {
+        from: PathBuf,
+        to: PathBuf,
+    },
+    /// Print the most recently created files and directories, newest first
+    Recent {
+        #[arg(default_value_t = 10)]
+        limit: usize,
+    },
+    /// Print the most recently deleted files and directories with deletion times, newest first
+    RecentlyDeleted {
+        #[arg(default_value_t = 10)]
+        limit: usize,
+    },
+    /// Print daemon diagnostics, failing if any watched directory is inaccessible
+    Health,
+    /// Repair stale watchers, printing the affected directories
+    Validate,
+    /// Remove indexed entries that no longer exist on the filesystem
+    PruneStale,
+    /// Print watched directories that no longer exist on the filesystem
+    Orphans {
+        /// Also remove them and their subtrees from the index
+        #[arg(long)]
+        clean: bool,
+    },
+    /// Write the index to a file
+    DumpIndex {
+        file: PathBuf,
+    },
+    /// Replace the index with one written by dump-index
+    LoadIndex {
+        file: PathBuf,
+    },
+    /// Print changes to the index as JSON lines until the daemon quits
+    Subscribe,
+    /// Start an interactive prompt accepting subcommands line by line
+    Interactive,
 }
 
+/// A failed subcommand, with the message to print. `None` if the subcommand already printed
+/// why it failed
+struct Failure(Option<String>);
+
+/// Turn an error into a descriptive `Failure` instead of panicking
+trait OrFail<T> {
+    fn or_fail(self, msg: &str) -> Result<T, Failure>;
+}
+
+impl<T, E: Display> OrFail<T> for Result<T, E> {
+    fn or_fail(self, msg: &str) -> Result<T, Failure> {
+        self.map_err(|why| Failure(Some(format!("{}: {}", msg, why))))
+    }
+}
+
+/// For lookups that answer `None` when nothing matched, reported as not found
+impl<T> OrFail<T> for Option<T> {
+    fn or_fail(self, msg: &str) -> Result<T, Failure> {
+        self.ok_or_else(|| Failure(Some(format!("{}: Not found", msg))))
+    }
+}
+
+/// Print a node and its children below it, drawing the branches with box characters
+fn print_tree(node: &TreeNode, prefix: &str) {
+    if prefix.is_empty() {
+        println!("{}", node.name);
+    }
+
+    for (i, child) in node.children.iter().enumerate() {
+        let last = i + 1 == node.children.len();
+        println!(
+            "{}{}{}{}",
+            prefix,
+            if last { "└── " } else { "├── " },
+            child.name,
+            if child.is_dir { "/" } else { "" }
+        );
+        print_tree(
+            child,
+            &format!("{}{}", prefix, if last { "    " } else { "│   " }),
+        );
+    }
+}
+
+/// Completes subcommand names in interactive mode
+struct SubcommandHelper {
+    subcommands: Vec<String>,
+}
+
+impl Completer for SubcommandHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+
+        // Only the first word is a subcommand name
+        if line.contains(char::is_whitespace) {
+            return Ok((pos, Vec::new()));
+        }
+
+        Ok((
+            0,
+            self.subcommands
+                .iter()
+                .filter(|name| name.starts_with(line))
+                .cloned()
+                .collect(),
+        ))
+    }
+}
+
+impl Hinter for SubcommandHelper {
+    type Hint = String;
+}
+
+impl Highlighter for SubcommandHelper {}
+
+impl Validator for SubcommandHelper {}
+
+impl Helper for SubcommandHelper {}
+
 fn main() {
     let opts = Opts::parse();
 
+    warn_on_version_mismatch();
+    if let Err(Failure(message)) = run(opts) {
+        if let Some(message) = message {
+            eprintln!("{}", message);
+        }
+        process::exit(1);
+    }
+}
+
+/// Warn about a daemon with a different minor version and exit on a different major version,
+/// connection errors are left for the subcommand to report
+fn warn_on_version_mismatch() {
+    if let Err(Error::VersionMismatch { client, server }) = check_version() {
+        let major = |version: &str| version.split('.').next().unwrap_or_default().to_string();
+        if major(&client) != major(&server) {
+            eprintln!(
+                "Error: Client version {} is incompatible with daemon version {}",
+                client, server
+            );
+            process::exit(1);
+        }
+        eprintln!(
+            "Warning: Client version {} differs from daemon version {}",
+            client, server
+        );
+
+        // Already warned, let the commands through
+        env::set_var("KIDEX_SKIP_VERSION_CHECK", "1");
+    }
+}
+
+/// Run a single subcommand, leaving exiting on failure to the caller
+fn run(opts: Opts) -> Result<(), Failure> {
     match opts.subcommand {
-        Command::Shutdown => {
-            shutdown_server().expect("Failed to shut down server");
+        Command::Shutdown { graceful } => {
+            if graceful {
+                graceful_shutdown()
+            } else {
+                shutdown_server()
+            }
+            .or_fail("Failed to shut down server")?;
+            println!("Success!");
+        }
+        Command::ReloadConfig { force } => {
+            let result = if force {
+                force_reload_config()
+            } else {
+                reload_config()
+            }
+            .or_fail("Failed to reload config")?;
+            for warning in &result.warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            println!(
+                "Success! Added {} watch dirs, removed {}, {} unchanged",
+                result.added_dirs.len(),
+                result.removed_dirs.len(),
+                result.unchanged_dirs.len()
+            );
+        }
+        Command::RegenerateIndex { verbose } => {
+            let warnings = regenerate_index_with_progress(|current, total| {
+                if verbose {
+                    match total {
+                        Some(total) => eprint!("\rIndexed {}/{} WatchDirs", current, total),
+                        None => eprint!("\rIndexed {} WatchDirs", current),
+                    }
+                }
+            })
+            .or_fail("Failed to regenerate index")?;
+            if verbose {
+                eprintln!();
+            }
+            for warning in warnings {
+                eprintln!("Warning: {}", warning);
+            }
             println!("Success!");
         }
-        Command::ReloadConfig => {
-            reload_config().expect("Failed to reload config");
+        Command::AtDepth { depth, root } => {
+            let root = root.map(|root| std::path::absolute(&root).unwrap_or(root));
+            for entry in get_at_depth(depth, root).or_fail("Failed to get entries")? {
+                println!("{}", entry.path.display());
+            }
+        }
+        Command::ByName { filename } => {
+            for entry in find_by_name(&filename).or_fail("Failed to find by name")? {
+                println!("{}", entry.path.display());
+            }
+        }
+        Command::Symlinks { root } => {
+            let root = root.map(|root| std::path::absolute(&root).unwrap_or(root));
+            for entry in get_symlinks(root).or_fail("Failed to get symlinks")? {
+                let target = entry.symlink_target.unwrap_or_default();
+                // Following the link fails if whatever it points to is gone
+                if entry.path.exists() {
+                    println!("{} -> {}", entry.path.display(), target.display());
+                } else {
+                    println!("{} -> {} (broken)", entry.path.display(), target.display());
+                }
+            }
+        }
+        Command::EmptyDirs { root } => {
+            let root = root.map(|root| std::path::absolute(&root).unwrap_or(root));
+            for entry in get_empty_dirs(root).or_fail("Failed to get empty directories")? {
+                println!("{}", entry.path.display());
+            }
+        }
+        Command::Largest { limit, root } => {
+            let root = root.map(|root| std::path::absolute(&root).unwrap_or(root));
+            for entry in get_largest_files(limit, root).or_fail("Failed to get largest files")? {
+                println!("{}", entry.path.display());
+            }
+        }
+        Command::Fuzzy { query, limit } => {
+            for entry in fuzzy_search(&query, limit).or_fail("Failed to search index")? {
+                println!("{}", entry.path.display());
+            }
+        }
+        Command::Extensions { root } => {
+            let root = root.map(|root| std::path::absolute(&root).unwrap_or(root));
+            for (ext, count) in list_extensions(root).or_fail("Failed to list extensions")? {
+                println!("{}\t{}", count, ext);
+            }
+        }
+        Command::Exact { path } => {
+            let path = std::path::absolute(&path).unwrap_or(path);
+            let entry = query_index_exact(path).or_fail("Failed to look up path")?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&entry).expect("Failed to serialize data")
+            );
+        }
+        Command::Count { path } => {
+            println!(
+                "{}",
+                get_index_count(path).or_fail("Failed to get index count")?
+            );
+        }
+        Command::GetIndex {
+            path,
+            json_pretty,
+            json_compact: _,
+        } => {
+            let index = get_index(path).or_fail("Failed to get index")?;
+            let json = if json_pretty {
+                serde_json::to_string_pretty(&index)
+            } else {
+                serde_json::to_string(&index)
+            };
+            println!("{}", json.expect("Failed to serialize data"));
+        }
+        Command::Tree { path } => {
+            let tree = get_index_as_tree(path).or_fail("Failed to get index")?;
+            if tree.name.is_empty() {
+                // Every root is printed as its own tree
+                for root in &tree.children {
+                    print_tree(root, "");
+                }
+            } else {
+                print_tree(&tree, "");
+            }
+        }
+        Command::GetPid => {
+            println!("{}", get_pid().or_fail("Failed to get daemon PID")?);
+        }
+        Command::WatchCount => {
+            let (current, max, warning) =
+                get_descriptor_count().or_fail("Failed to get watcher count")?;
+            println!("{}/{}", current, max);
+            if let Some(warning) = warning {
+                eprintln!("Warning: {}", warning);
+            }
+        }
+        Command::DescriptorInfo { id } => {
+            if !opts.debug {
+                return Err(Failure(Some(
+                    "Error: descriptor-info requires the --debug flag".to_string(),
+                )));
+            }
+            let (path, child_count, children, parent_path) =
+                get_index_for_descriptor(id).or_fail("Failed to get descriptor info")?;
+            println!("Path: {}", path.display());
+            if let Some(parent_path) = parent_path {
+                println!("Parent: {}", parent_path.display());
+            }
+            println!("Children: {}", child_count);
+            for child in children {
+                println!("  {}", child.path.display());
+            }
+        }
+        Command::Stats => {
+            let stats = get_stats().or_fail("Failed to get index statistics")?;
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&stats).expect("Failed to serialize data")
+            );
+        }
+        Command::AddIgnorePattern { pattern } => {
+            add_ignore_pattern(pattern).or_fail("Failed to add ignore pattern")?;
             println!("Success!");
         }
-        Command::RegenerateIndex => {
-            regenerate_index().expect("Failed to regenerate index");
+        Command::RemoveIgnorePattern { pattern } => {
+            remove_ignore_pattern(pattern).or_fail("Failed to remove ignore pattern")?;
             println!("Success!");
         }
-        Command::GetIndex { path } => {
-            let index = get_index(path).expect("Failed to get index");
+        Command::TestIgnore {
+            pattern,
+            path,
+            verbose,
+        } => {
+            // Checked locally, like the daemon would
+            let pattern = Pattern::new(&pattern).or_fail("Invalid pattern")?;
+            if verbose {
+                println!("Parsed pattern: {:?}", pattern);
+            }
+            if pattern.matches(&path.to_string_lossy()) {
+                println!("Ignored");
+            } else {
+                println!("Not ignored");
+                return Err(Failure(None));
+            }
+        }
+        Command::WhichWatchDir { path } => {
+            // The daemon only knows absolute paths
+            let path = std::path::absolute(&path).unwrap_or(path);
+            let info = get_watch_dir_for_path(path)
+                .or_fail("Failed to get WatchDir")?
+                .or_fail("Path is not inside any WatchDir")?;
             println!(
                 "{}",
-                serde_json::to_string_pretty(&index).expect("Failed to serialize data")
+                serde_json::to_string_pretty(&info).expect("Failed to serialize data")
             );
         }
+        Command::ParentChain { path } => {
+            let path = std::path::absolute(&path).unwrap_or(path);
+            for entry in get_parent_chain(path).or_fail("Failed to get parent chain")? {
+                println!("{}", entry.path.display());
+            }
+        }
+        Command::ListIgnorePatterns => {
+            let patterns = list_ignore_patterns().or_fail("Failed to list ignore patterns")?;
+            println!("Config patterns:");
+            for pattern in patterns.config_patterns {
+                println!("  {}", pattern);
+            }
+            println!("Runtime patterns:");
+            for pattern in patterns.runtime_patterns {
+                println!("  {}", pattern);
+            }
+        }
+        Command::Roots => {
+            for root in get_roots().or_fail("Failed to get roots")? {
+                println!("{}", root.display());
+            }
+        }
+        Command::SetWatchMask { path, events } => {
+            set_watch_mask(path, events).or_fail("Failed to set watch mask")?;
+            println!("Success!");
+        }
+        Command::UpdateIgnored { path, patterns } => {
+            update_ignored_for(path, patterns).or_fail("Failed to update ignore patterns")?;
+            println!("Success!");
+        }
+        Command::SimulateCreate { path } => {
+            simulate_create(path).or_fail("Failed to simulate creation")?;
+            println!("Success!");
+        }
+        Command::SimulateDelete { path } => {
+            simulate_delete(path).or_fail("Failed to simulate deletion")?;
+            println!("Success!");
+        }
+        Command::Rewatch { path } => {
+            force_rewatch(path).or_fail("Failed to rewatch directory")?;
+            println!("Success!");
+        }
+        Command::MovePath { from, to } => {
+            move_path(from, to).or_fail("Failed to move path")?;
+            println!("Success!");
+        }
+        Command::Recent { limit } => {
+            for entry in
+                get_recently_created(limit).or_fail("Failed to get recently created files")?
+            {
+                println!("{}", entry.path.display());
+            }
+        }
+        Command::RecentlyDeleted { limit } => {
+            for entry in
+                get_recently_deleted(limit).or_fail("Failed to get recently deleted files")?
+            {
+                println!(
+                    "{} {}",
+                    humantime::format_rfc3339_seconds(entry.deleted_at),
+                    entry.path.display()
+                );
+            }
+        }
+        Command::Health => {
+            let health = health_check().or_fail("Failed to check daemon health")?;
+            println!("Version: {}", health.version);
+            println!("Uptime: {}s", health.uptime_secs);
+            match health.max_watchers {
+                Some(max) => println!("Watchers: {}/{}", health.watcher_count, max),
+                None => println!("Watchers: {}", health.watcher_count),
+            }
+            println!("Indexed files: {}", health.file_count);
+            println!("Indexed directories: {}", health.dir_count);
+
+            if !health.inaccessible_dirs.is_empty() {
+                for dir in &health.inaccessible_dirs {
+                    eprintln!("Inaccessible directory: {}", dir.display());
+                }
+                return Err(Failure(None));
+            }
+        }
+        Command::Validate => {
+            for path in validate_watchers().or_fail("Failed to validate watchers")? {
+                println!("{}", path.display());
+            }
+        }
+        Command::PruneStale => {
+            let pruned = prune_stale().or_fail("Failed to prune stale entries")?;
+            println!("Success! Pruned {} stale entries", pruned);
+        }
+        Command::Orphans { clean } => {
+            let orphans = if clean {
+                clean_orphans().or_fail("Failed to clean orphaned directories")?
+            } else {
+                get_orphaned_paths().or_fail("Failed to get orphaned directories")?
+            };
+            for path in orphans {
+                println!("{}", path.display());
+            }
+        }
+        Command::DumpIndex { file } => {
+            let blob = dump_index().or_fail("Failed to dump index")?;
+            fs::write(file, blob).or_fail("Failed to write index dump")?;
+            println!("Success!");
+        }
+        Command::LoadIndex { file } => {
+            let blob = fs::read(file).or_fail("Failed to read index dump")?;
+            load_index(blob).or_fail("Failed to load index")?;
+            println!("Success!");
+        }
+        Command::Subscribe => {
+            for event in subscribe().or_fail("Failed to subscribe")? {
+                let event = event.or_fail("Failed to read event")?;
+                println!(
+                    "{}",
+                    serde_json::to_string(&event).expect("Failed to serialize data")
+                );
+            }
+        }
+        Command::Interactive => interactive()?,
     }
+
+    Ok(())
+}
+
+/// Read subcommands from the user until EOF or interrupt
+fn interactive() -> Result<(), Failure> {
+    let mut editor = Editor::<SubcommandHelper, DefaultHistory>::new()
+        .or_fail("Failed to create line editor")?;
+    editor.set_helper(Some(SubcommandHelper {
+        subcommands: Opts::command()
+            .get_subcommands()
+            .map(|subcommand| subcommand.get_name().to_string())
+            .filter(|name| name != "interactive")
+            .collect(),
+    }));
+
+    loop {
+        let line = match editor.readline("kidex> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Eof | ReadlineError::Interrupted) => break,
+            Err(why) => {
+                eprintln!("Failed to read line: {}", why);
+                break;
+            }
+        };
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let _ = editor.add_history_entry(line.as_str());
+
+        match Opts::try_parse_from(iter::once("kidex-client").chain(line.split_whitespace())) {
+            Ok(Opts {
+                subcommand: Command::Interactive,
+                ..
+            }) => eprintln!("Already in interactive mode"),
+            Ok(opts) => {
+                // Keep the session going, only reporting the failure
+                if let Err(Failure(Some(message))) = run(opts) {
+                    eprintln!("{}", message);
+                }
+            }
+            Err(why) => {
+                let _ = why.print();
+            }
+        }
+    }
+
+    Ok(())
 }
@@ -1,28 +1,419 @@
-use std::path::PathBuf;
+use std::{path::PathBuf, time::SystemTime};
 
 use serde::{Deserialize, Serialize};
 
 pub const DEFAULT_SOCKET: &str = "/tmp/kidex.sock";
 
-#[derive(Deserialize, Serialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub enum IpcCommand {
     FullIndex,
     Quit,
+    /// Quit once the running operations have finished, refusing new requests meanwhile
+    GracefulShutdown,
     Reload,
+    /// Reload the config right away, without waiting for `reload_debounce_ms`
+    ForceReload,
     GetIndex(Option<PathBuf>),
+    /// Get the entries matching a structural filter
+    GetIndexFiltered(IndexFilter),
+    /// Get the indexed files with an extension, ignoring case
+    GetIndexByExtension(String),
+    /// Get the index as a tree, of everything or an indexed directory
+    GetIndexAsTree(Option<PathBuf>),
+    GetPid,
+    Stats,
+    AddIgnorePattern(String),
+    RemoveIgnorePattern(String),
+    ListIgnorePatterns,
+    /// Check if an ignore pattern matches a path, without adding the pattern
+    TestIgnorePattern(String, PathBuf),
+    GetRoots,
+    SetConfig(ConfigPatch),
+    HealthCheck,
+    /// Get the most recently created entries, newest first
+    GetRecentlyCreated {
+        limit: usize,
+    },
+    /// Get the most recently deleted entries, newest first
+    GetRecentlyDeleted {
+        limit: usize,
+    },
+    /// Check for and repair stale inotify watchers
+    Validate,
+    /// Get the directory structure of the index as a compressed blob
+    DumpIndex,
+    /// Replace the index with a blob from `DumpIndex`
+    LoadIndex(Vec<u8>),
+    /// Get the socket streaming `ChangeEvent`s
+    Subscribe,
+    /// Remove indexed entries that no longer exist on the filesystem
+    PruneStale,
+    /// Get the watched directories that no longer exist on the filesystem
+    GetOrphanedDescriptors,
+    /// Remove watched directories that no longer exist, along with their subtrees
+    CleanOrphans,
+    /// Get the changes made to the index after the given generation
+    GetIndexSince(u64),
+    /// Get the amount of indexed entries, under a directory if one is given
+    GetIndexCount(Option<PathBuf>),
+    /// Get every file extension in the index with the amount of files having it,
+    /// under a directory if one is given
+    ListExtensions(Option<PathBuf>),
+    /// Get the entries nested exactly this many levels below a directory, or below their
+    /// WatchDir root if none is given. Direct children are at depth 1
+    GetIndexDepth(usize, Option<PathBuf>),
+    /// Get every entry with exactly this file name
+    GetDuplicateNames(String),
+    /// Get every indexed symlink, under a directory if one is given
+    GetSymlinks(Option<PathBuf>),
+    /// Get the watched directories without any indexed children, under a directory if one is given
+    GetEmptyDirs(Option<PathBuf>),
+    /// Get the largest indexed files, under a directory if one is given, largest first
+    GetIndexLargestFiles {
+        limit: usize,
+        root: Option<PathBuf>,
+    },
+    /// Get the entries with file names most similar to the query, best matches first
+    QueryIndexFuzzy(String, usize),
+    /// Get the entry at exactly this full path
+    QueryIndexExact(PathBuf),
+    /// Get detailed statistics about an indexed directory
+    GetWatchDirStats(PathBuf),
+    /// Get the configured WatchDir responsible for a path
+    GetWatchDirForPath(PathBuf),
+    /// Get the ancestor directories of an indexed entry, from its WatchDir root down
+    GetParentChain(PathBuf),
+    /// Get the version of the daemon
+    GetVersion,
+    /// Get the amount of inotify watchers in use and the system limit
+    GetDescriptorCount,
+    /// Get the indexed directory of a raw inotify watch descriptor, for debugging
+    GetIndexForDescriptor(u64),
+    /// Replace the inotify events watched for an indexed directory and its subdirectories
+    SetWatchMask(PathBuf, Vec<String>),
+    /// Replace the ignore patterns of an indexed root directory and recrawl it,
+    /// without reindexing everything else
+    UpdateIgnoredFor(PathBuf, Vec<String>),
+    /// Index a path and send a `Created` change to subscribers as if it had just been created,
+    /// requires `allow_simulate_commands`
+    SimulateCreate(PathBuf),
+    /// Un-index a path and send a `Deleted` change to subscribers as if it had just been deleted,
+    /// requires `allow_simulate_commands`
+    SimulateDelete(PathBuf),
+    /// Re-register the inotify watcher of a single indexed directory
+    ForceRewatch(PathBuf),
+    /// Update the index for a rename it did not see happen
+    MovePath {
+        from: PathBuf,
+        to: PathBuf,
+    },
 }
 
-#[derive(Deserialize, Serialize)]
+/// A command tagged with an ID, answered with an `IpcReply` carrying the same ID
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IpcRequest {
+    pub id: u64,
+    pub command: IpcCommand,
+}
+
+/// The response to an `IpcRequest`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct IpcReply<R = IpcResponse> {
+    pub id: u64,
+    pub response: R,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
 pub enum IpcResponse {
     Success,
+    /// Sent right away for a `FullIndex`, `Reload` or `ForceReload` request with an ID, before the result,
+    /// followed by a null byte. The ID is that of the `IpcReply` carrying it
+    Ack,
     NotFound,
     Index(Vec<IndexEntry>),
+    Tree(TreeNode),
+    Busy,
+    Pid(u32),
+    Version(String),
+    DescriptorCount {
+        current: usize,
+        max: usize,
+        /// Set when over 90% of the watchers are in use
+        warning: Option<String>,
+    },
+    DirectoryInfo {
+        path: PathBuf,
+        child_count: usize,
+        /// The direct children of the directory
+        children: Vec<IndexEntry>,
+        /// `None` for WatchDir roots
+        parent_path: Option<PathBuf>,
+    },
+    Stats(IndexStatistics),
+    Patterns(PatternsInfo),
+    TestResult {
+        matches: bool,
+        /// The `Debug` output of the compiled pattern, for troubleshooting only. It is not a
+        /// pattern string and its format may change with the globbing library
+        pattern_debug: String,
+    },
+    ReloadResult(ReloadResult),
+    Roots(Vec<PathBuf>),
+    Health(HealthInfo),
+    RecentFiles(Vec<IndexEntry>),
+    Count(usize),
+    Entry(IndexEntry),
+    /// Extensions without the leading `.` and their file counts, most common first
+    Extensions(Vec<(String, usize)>),
+    /// Directories from a WatchDir root down to the parent of the requested entry
+    ParentChain(Vec<IndexEntry>),
+    RecentlyDeleted(Vec<DeletedEntry>),
+    /// Directories whose watchers were stale and got reindexed
+    StalePaths(Vec<PathBuf>),
+    Blob(Vec<u8>),
+    /// Amount of removed stale entries
+    Pruned(usize),
+    /// Watched directories that no longer exist on the filesystem
+    OrphanedPaths(Vec<PathBuf>),
+    WatchDirStats(WatchDirStats),
+    /// `None` if no WatchDir contains the path
+    WatchDirForPath(Option<WatchDirInfo>),
+    IndexDiff {
+        current_generation: u64,
+        changes: Vec<IndexChange>,
+    },
+    /// The full index, sent instead of `IndexDiff` when the changes are no longer known
+    IndexSnapshot {
+        current_generation: u64,
+        entries: Vec<IndexEntry>,
+    },
+    /// Sent before the result of `FullIndex` as each WatchDir is crawled,
+    /// followed by a null byte
+    Progress {
+        current: usize,
+        total: Option<usize>,
+    },
+    /// The command succeeded, but with non-fatal issues
+    Warning(Vec<String>),
+    SubscribeAck {
+        events_socket: PathBuf,
+    },
+    Error(String),
 }
 
-#[derive(Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct IndexEntry {
     pub path: PathBuf,
     pub directory: bool,
+    /// Levels `path` is nested below `watch_dir_root`, 1 for its direct children and 0 for
+    /// the root itself
+    #[serde(default)]
+    pub depth: usize,
+    #[serde(default)]
+    pub symlink: bool,
+    /// Where the symlink points to, as stored in the link
+    #[serde(default)]
+    pub symlink_target: Option<PathBuf>,
+    /// The configured root directory the entry was indexed under
+    #[serde(default)]
+    pub watch_dir_root: Option<PathBuf>,
+}
+
+/// A file or directory in the index tree, with its children sorted by name
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct TreeNode {
+    /// File name, or the full path for the top-level directory
+    pub name: String,
+    pub is_dir: bool,
+    pub children: Vec<TreeNode>,
+}
+
+/// A file or directory deleted while the daemon was running
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct DeletedEntry {
+    pub path: PathBuf,
+    pub deleted_at: SystemTime,
+}
+
+/// A single change to the index
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum IndexChange {
+    Added(IndexEntry),
+    Removed(PathBuf),
+}
+
+/// Result of an incremental sync
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub enum IndexUpdate {
+    /// Changes to apply to the previously synced index
+    Diff(Vec<IndexChange>),
+    /// The full index, replacing the previously synced one
+    Snapshot(Vec<IndexEntry>),
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct IndexStatistics {
+    /// Amount of directories with an inotify watcher
+    pub watched_dirs: usize,
+    /// Amount of indexed files and directories
+    pub indexed_entries: usize,
+    /// Rough estimate of the memory used by the index, in bytes
+    pub memory_usage_estimate: usize,
+    /// Per root directory counts
+    pub watch_dirs: Vec<WatchDirStatistics>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WatchDirStatistics {
+    pub path: PathBuf,
+    /// Amount of indexed files, including subdirectories
+    pub file_count: usize,
+    /// Amount of indexed directories, including subdirectories
+    pub dir_count: usize,
+}
+
+/// A change to the index, streamed to subscribers
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ChangeEvent {
+    pub kind: EventKind,
+    pub path: PathBuf,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum EventKind {
+    Created,
+    Deleted,
+    MovedFrom,
+    MovedTo,
+    /// Emitted by `util::reconnecting_stream` after reconnecting to a restarted daemon,
+    /// changes made in between were missed. `path` is empty
+    DaemonRestarted,
+    /// The subscriber fell behind and events were dropped, the stream continues with
+    /// the newest changes. `path` is empty
+    Lagged,
+}
+
+/// Detailed statistics about a single indexed directory
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WatchDirStats {
+    pub path: PathBuf,
+    /// Amount of indexed files, including subdirectories
+    pub file_count: usize,
+    /// Amount of indexed directories, including subdirectories
+    pub dir_count: usize,
+    /// Amount of inotify watchers used by the directory and its subdirectories
+    pub total_watch_descriptors: usize,
+    /// The indexed file with the oldest modification time
+    pub oldest_indexed_entry: Option<PathBuf>,
+    /// The last 5 files created while the daemon was running, newest first
+    pub recently_indexed_files: Vec<PathBuf>,
+}
+
+/// The configuration of a WatchDir
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct WatchDirInfo {
+    pub path: PathBuf,
+    pub recurse: bool,
+    /// Ignore patterns, including the global ones
+    pub ignored: Vec<String>,
+    pub ignored_dirs: Vec<String>,
+    pub polling_interval_secs: Option<u64>,
+}
+
+/// Diagnostics about the state of the daemon
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct HealthInfo {
+    pub version: String,
+    pub uptime_secs: u64,
+    /// Amount of active inotify watchers
+    pub watcher_count: usize,
+    /// The system limit for inotify watchers, if it could be read
+    pub max_watchers: Option<usize>,
+    pub file_count: usize,
+    pub dir_count: usize,
+    /// Configured directories that currently do not exist
+    pub inaccessible_dirs: Vec<PathBuf>,
+}
+
+/// The active ignore patterns, by where they come from
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct PatternsInfo {
+    /// Global patterns from the config file
+    pub config_patterns: Vec<String>,
+    /// Patterns added with `AddIgnorePattern`
+    pub runtime_patterns: Vec<String>,
+}
+
+/// Which kinds of entries an `IndexFilter` lets through
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TypeFilter {
+    #[default]
+    All,
+    Files,
+    Directories,
+}
+
+/// Structural filter for index entries, without any scoring
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct IndexFilter {
+    #[serde(default)]
+    pub type_filter: TypeFilter,
+    /// Only entries inside this indexed directory, everything if `None`
+    #[serde(default)]
+    pub root_path: Option<PathBuf>,
+    /// Include entries inside directories or with names starting with a `.`
+    #[serde(default)]
+    pub hidden: bool,
+    /// Levels below `root_path` to include, or below the WatchDir root if it is not set
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+}
+
+impl IndexFilter {
+    /// Check if an entry passes the filter, `root_path` is expected to be applied already
+    pub fn matches(&self, entry: &IndexEntry) -> bool {
+        let type_matches = match self.type_filter {
+            TypeFilter::All => true,
+            TypeFilter::Files => !entry.directory,
+            TypeFilter::Directories => entry.directory,
+        };
+
+        // Only the part below the root counts, roots may be inside hidden directories
+        let relative = self
+            .root_path
+            .as_ref()
+            .or(entry.watch_dir_root.as_ref())
+            .and_then(|root| entry.path.strip_prefix(root).ok())
+            .unwrap_or(&entry.path);
+
+        let hidden_matches = self.hidden
+            || !relative
+                .iter()
+                .any(|component| component.to_string_lossy().starts_with('.'));
+        let depth_matches = self
+            .max_depth
+            .is_none_or(|max_depth| relative.iter().count() <= max_depth);
+
+        type_matches && hidden_matches && depth_matches
+    }
+}
+
+/// Config fields to change at runtime, `None` leaves a field untouched
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ConfigPatch {
+    pub ignored: Option<Vec<String>>,
+    pub concurrent_index_workers: Option<usize>,
+}
+
+/// Watched directories affected by a config reload
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct ReloadResult {
+    pub added_dirs: Vec<PathBuf>,
+    pub removed_dirs: Vec<PathBuf>,
+    pub unchanged_dirs: Vec<PathBuf>,
+    /// Non-fatal issues encountered while reindexing
+    #[serde(default)]
+    pub warnings: Vec<String>,
 }
 
 #[cfg(feature = "util")]
@@ -30,18 +421,33 @@ pub mod util {
     use std::{
         env,
         fmt::Display,
-        io::{self, Read, Write},
+        io::{self, BufRead, BufReader, Read, Write},
         os::unix::net::UnixStream,
         path::PathBuf,
+        sync::OnceLock,
     };
 
-    use super::{IndexEntry, IpcCommand, IpcResponse, DEFAULT_SOCKET};
+    use super::{
+        ChangeEvent, ConfigPatch, DeletedEntry, HealthInfo, IndexEntry, IndexFilter,
+        IndexStatistics, IndexUpdate, IpcCommand, IpcReply, IpcRequest, IpcResponse, PatternsInfo,
+        ReloadResult, TreeNode, WatchDirInfo, WatchDirStats, DEFAULT_SOCKET,
+    };
+    #[cfg(feature = "stream")]
+    use {super::EventKind, std::time::Duration};
 
     #[derive(Debug)]
     pub enum Error {
         Io(io::Error),
         Serde(serde_json::Error),
         NotFound,
+        Busy,
+        /// The daemon processed the command but reported an error
+        Remote(String),
+        /// The major or minor versions of the client and daemon differ
+        VersionMismatch {
+            client: String,
+            server: String,
+        },
         Unknown,
     }
 
@@ -63,6 +469,13 @@ pub mod util {
                 Error::Io(why) => write!(f, "An IO error occurred: {}", why),
                 Error::Serde(why) => write!(f, "A se/deserialization error occurred: {}", why),
                 Error::NotFound => write!(f, "Requested path not found"),
+                Error::Busy => write!(f, "A reindex is already in progress, please wait."),
+                Error::Remote(why) => write!(f, "The daemon returned an error: {}", why),
+                Error::VersionMismatch { client, server } => write!(
+                    f,
+                    "Client version {} is incompatible with daemon version {}",
+                    client, server
+                ),
                 Error::Unknown => write!(f, "An unknown error occurred"),
             }
         }
@@ -71,6 +484,11 @@ pub mod util {
     impl std::error::Error for Error {}
 
     fn fetch(command: &IpcCommand) -> Result<IpcResponse, Error> {
+        check_version()?;
+        fetch_unchecked(command)
+    }
+
+    fn fetch_unchecked(command: &IpcCommand) -> Result<IpcResponse, Error> {
         let mut stream =
             UnixStream::connect(env::var("SOCKET_PATH").unwrap_or(DEFAULT_SOCKET.to_string()))?;
 
@@ -83,20 +501,574 @@ pub mod util {
 
         stream.read_to_end(&mut buf)?;
 
-        Ok(serde_json::from_slice(&buf)?)
+        match serde_json::from_slice(&buf)? {
+            IpcResponse::Error(why) => Err(Error::Remote(why)),
+            response => Ok(response),
+        }
+    }
+
+    /// Get the entries matching a filter, `Error::NotFound` if its root is not indexed
+    pub fn get_index_filtered(filter: IndexFilter) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::GetIndexFiltered(filter))? {
+            IpcResponse::Index(index) => Ok(index),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the indexed files with an extension like `pdf` or `.pdf`, ignoring case
+    pub fn get_by_extension(ext: &str) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::GetIndexByExtension(ext.to_string()))? {
+            IpcResponse::Index(index) => Ok(index),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the index as a tree. Without a root, the indexed roots are the children
+    /// of a nameless node
+    pub fn get_index_as_tree(root: Option<PathBuf>) -> Result<TreeNode, Error> {
+        match fetch(&IpcCommand::GetIndexAsTree(root))? {
+            IpcResponse::Tree(tree) => Ok(tree),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
     }
 
     pub fn get_index(path: Option<PathBuf>) -> Result<Vec<IndexEntry>, Error> {
         match fetch(&IpcCommand::GetIndex(path))? {
             IpcResponse::Index(index) => Ok(index),
             IpcResponse::NotFound => Err(Error::NotFound),
-            _ => unreachable!(),
+            _ => Err(Error::Unknown),
         }
     }
 
-    pub fn regenerate_index() -> Result<(), Error> {
-        match fetch(&IpcCommand::FullIndex)? {
+    /// Get every file extension with its file count, most common first
+    pub fn list_extensions(root: Option<PathBuf>) -> Result<Vec<(String, usize)>, Error> {
+        match fetch(&IpcCommand::ListExtensions(root))? {
+            IpcResponse::Extensions(extensions) => Ok(extensions),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the entries nested exactly `depth` levels below `root`, or below their WatchDir root
+    pub fn get_at_depth(depth: usize, root: Option<PathBuf>) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::GetIndexDepth(depth, root))? {
+            IpcResponse::Index(entries) => Ok(entries),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get every indexed file and directory named exactly `name`
+    pub fn find_by_name(name: &str) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::GetDuplicateNames(name.to_string()))? {
+            IpcResponse::Index(entries) => Ok(entries),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get every indexed symlink along with its target
+    pub fn get_symlinks(root: Option<PathBuf>) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::GetSymlinks(root))? {
+            IpcResponse::Index(entries) => Ok(entries),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the watched directories that have no indexed children
+    pub fn get_empty_dirs(root: Option<PathBuf>) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::GetEmptyDirs(root))? {
+            IpcResponse::Index(entries) => Ok(entries),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the `limit` largest indexed files, largest first
+    pub fn get_largest_files(
+        limit: usize,
+        root: Option<PathBuf>,
+    ) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::GetIndexLargestFiles { limit, root })? {
+            IpcResponse::Index(entries) => Ok(entries),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get up to `limit` entries with file names similar to `query`, best matches first
+    pub fn fuzzy_search(query: &str, limit: usize) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::QueryIndexFuzzy(query.to_string(), limit))? {
+            IpcResponse::Index(entries) => Ok(entries),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the indexed file or directory at exactly this full path
+    pub fn query_index_exact(path: PathBuf) -> Result<IndexEntry, Error> {
+        match fetch(&IpcCommand::QueryIndexExact(path))? {
+            IpcResponse::Entry(entry) => Ok(entry),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the amount of indexed entries without transferring their paths
+    pub fn get_index_count(root: Option<PathBuf>) -> Result<usize, Error> {
+        match fetch(&IpcCommand::GetIndexCount(root))? {
+            IpcResponse::Count(count) => Ok(count),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the changes made after the given generation, along with the current generation
+    pub fn get_index_since(generation: u64) -> Result<(u64, IndexUpdate), Error> {
+        match fetch(&IpcCommand::GetIndexSince(generation))? {
+            IpcResponse::IndexDiff {
+                current_generation,
+                changes,
+            } => Ok((current_generation, IndexUpdate::Diff(changes))),
+            IpcResponse::IndexSnapshot {
+                current_generation,
+                entries,
+            } => Ok((current_generation, IndexUpdate::Snapshot(entries))),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn get_watch_dir_stats(path: PathBuf) -> Result<WatchDirStats, Error> {
+        match fetch(&IpcCommand::GetWatchDirStats(path))? {
+            IpcResponse::WatchDirStats(stats) => Ok(stats),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn get_roots() -> Result<Vec<PathBuf>, Error> {
+        match fetch(&IpcCommand::GetRoots)? {
+            IpcResponse::Roots(roots) => Ok(roots),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn set_config(patch: ConfigPatch) -> Result<(), Error> {
+        match fetch(&IpcCommand::SetConfig(patch))? {
+            IpcResponse::Success => Ok(()),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn set_watch_mask(path: PathBuf, events: Vec<String>) -> Result<(), Error> {
+        match fetch(&IpcCommand::SetWatchMask(path, events))? {
+            IpcResponse::Success => Ok(()),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn update_ignored_for(path: PathBuf, patterns: Vec<String>) -> Result<(), Error> {
+        match fetch(&IpcCommand::UpdateIgnoredFor(path, patterns))? {
             IpcResponse::Success => Ok(()),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn simulate_create(path: PathBuf) -> Result<(), Error> {
+        match fetch(&IpcCommand::SimulateCreate(path))? {
+            IpcResponse::Success => Ok(()),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn simulate_delete(path: PathBuf) -> Result<(), Error> {
+        match fetch(&IpcCommand::SimulateDelete(path))? {
+            IpcResponse::Success => Ok(()),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn force_rewatch(path: PathBuf) -> Result<(), Error> {
+        match fetch(&IpcCommand::ForceRewatch(path))? {
+            IpcResponse::Success => Ok(()),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the ancestor directories of an indexed entry, for breadcrumbs
+    pub fn get_parent_chain(path: PathBuf) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::GetParentChain(path))? {
+            IpcResponse::ParentChain(chain) => Ok(chain),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the WatchDir responsible for a path, `None` if the path is not inside any of them
+    pub fn get_watch_dir_for_path(path: PathBuf) -> Result<Option<WatchDirInfo>, Error> {
+        match fetch(&IpcCommand::GetWatchDirForPath(path))? {
+            IpcResponse::WatchDirForPath(info) => Ok(info),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn move_path(from: PathBuf, to: PathBuf) -> Result<(), Error> {
+        match fetch(&IpcCommand::MovePath { from, to })? {
+            IpcResponse::Success => Ok(()),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn health_check() -> Result<HealthInfo, Error> {
+        match fetch(&IpcCommand::HealthCheck)? {
+            IpcResponse::Health(health) => Ok(health),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn get_recently_created(limit: usize) -> Result<Vec<IndexEntry>, Error> {
+        match fetch(&IpcCommand::GetRecentlyCreated { limit })? {
+            IpcResponse::RecentFiles(entries) => Ok(entries),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn get_recently_deleted(limit: usize) -> Result<Vec<DeletedEntry>, Error> {
+        match fetch(&IpcCommand::GetRecentlyDeleted { limit })? {
+            IpcResponse::RecentlyDeleted(entries) => Ok(entries),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn validate_watchers() -> Result<Vec<PathBuf>, Error> {
+        match fetch(&IpcCommand::Validate)? {
+            IpcResponse::StalePaths(paths) => Ok(paths),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn dump_index() -> Result<Vec<u8>, Error> {
+        match fetch(&IpcCommand::DumpIndex)? {
+            IpcResponse::Blob(blob) => Ok(blob),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn load_index(blob: Vec<u8>) -> Result<(), Error> {
+        match fetch(&IpcCommand::LoadIndex(blob))? {
+            IpcResponse::Success => Ok(()),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Connect to the event socket, yielding changes to the index until the daemon quits
+    pub fn subscribe() -> Result<impl Iterator<Item = Result<ChangeEvent, Error>>, Error> {
+        let events_socket = match fetch(&IpcCommand::Subscribe)? {
+            IpcResponse::SubscribeAck { events_socket } => events_socket,
+            _ => return Err(Error::Unknown),
+        };
+
+        let stream = BufReader::new(UnixStream::connect(events_socket)?);
+
+        Ok(stream
+            .split(0x0)
+            .map(|buf| Ok(serde_json::from_slice(&buf?)?)))
+    }
+
+    pub fn prune_stale() -> Result<usize, Error> {
+        match fetch(&IpcCommand::PruneStale)? {
+            IpcResponse::Pruned(pruned) => Ok(pruned),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn get_orphaned_paths() -> Result<Vec<PathBuf>, Error> {
+        match fetch(&IpcCommand::GetOrphanedDescriptors)? {
+            IpcResponse::OrphanedPaths(paths) => Ok(paths),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Remove orphaned watched directories, returning their paths
+    pub fn clean_orphans() -> Result<Vec<PathBuf>, Error> {
+        match fetch(&IpcCommand::CleanOrphans)? {
+            IpcResponse::OrphanedPaths(paths) => Ok(paths),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Connect to the event socket, asynchronously
+    #[cfg(feature = "stream")]
+    async fn subscribe_async() -> Result<tokio::io::BufReader<tokio::net::UnixStream>, Error> {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let mut stream = tokio::net::UnixStream::connect(
+            env::var("SOCKET_PATH").unwrap_or(DEFAULT_SOCKET.to_string()),
+        )
+        .await?;
+
+        let mut buf = serde_json::to_vec(&IpcCommand::Subscribe).unwrap();
+        buf.push(0x0);
+        stream.write_all(&buf).await?;
+
+        buf.clear();
+        stream.read_to_end(&mut buf).await?;
+
+        match serde_json::from_slice(&buf)? {
+            IpcResponse::SubscribeAck { events_socket } => Ok(tokio::io::BufReader::new(
+                tokio::net::UnixStream::connect(events_socket).await?,
+            )),
+            IpcResponse::Error(why) => Err(Error::Remote(why)),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Stream changes of the given kinds, reconnecting every `retry_interval` while the
+    /// daemon is unreachable. Reconnecting emits an `EventKind::DaemonRestarted` event
+    #[cfg(feature = "stream")]
+    pub fn reconnecting_stream(
+        kinds: Vec<EventKind>,
+        retry_interval: Duration,
+    ) -> impl futures::Stream<Item = Result<ChangeEvent, Error>> {
+        use tokio::io::AsyncBufReadExt;
+
+        // The current connection and whether it replaced a closed one
+        futures::stream::unfold(
+            (None, false),
+            move |(mut reader, mut restarted): (Option<tokio::io::BufReader<_>>, bool)| {
+                let kinds = kinds.clone();
+                async move {
+                    loop {
+                        let Some(stream) = &mut reader else {
+                            match subscribe_async().await {
+                                Ok(stream) => reader = Some(stream),
+                                Err(_) => tokio::time::sleep(retry_interval).await,
+                            }
+                            continue;
+                        };
+
+                        if restarted {
+                            let event = ChangeEvent {
+                                kind: EventKind::DaemonRestarted,
+                                path: PathBuf::new(),
+                            };
+                            return Some((Ok(event), (reader, false)));
+                        }
+
+                        let mut buf = Vec::new();
+                        match stream.read_until(0x0, &mut buf).await {
+                            // The daemon closed the connection
+                            Ok(0) | Err(_) => {
+                                reader = None;
+                                restarted = true;
+                            }
+                            Ok(_) => {
+                                buf.pop(); // Remove the delimiting null byte
+                                match serde_json::from_slice::<ChangeEvent>(&buf) {
+                                    Ok(event) if kinds.contains(&event.kind) => {
+                                        return Some((Ok(event), (reader, restarted)))
+                                    }
+                                    Ok(_) => (),
+                                    Err(why) => {
+                                        return Some((Err(why.into()), (reader, restarted)))
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+        )
+    }
+
+    /// The daemon version, asked only once per process
+    static SERVER_VERSION: OnceLock<String> = OnceLock::new();
+
+    /// Check that the daemon has the same major and minor version as this library,
+    /// skipped if `KIDEX_SKIP_VERSION_CHECK=1` is set. The daemon is asked for its version once,
+    /// later checks reuse the answer
+    pub fn check_version() -> Result<(), Error> {
+        if env::var("KIDEX_SKIP_VERSION_CHECK").is_ok_and(|skip| skip == "1") {
+            return Ok(());
+        }
+
+        let client = env!("CARGO_PKG_VERSION");
+        let server = match SERVER_VERSION.get() {
+            Some(version) => version.clone(),
+            None => {
+                let version = get_version()?;
+                SERVER_VERSION.get_or_init(|| version).clone()
+            }
+        };
+
+        let major_minor = |version: &str| version.split('.').take(2).collect::<Vec<_>>().join(".");
+        if major_minor(client) == major_minor(&server) {
+            Ok(())
+        } else {
+            Err(Error::VersionMismatch {
+                client: client.to_string(),
+                server,
+            })
+        }
+    }
+
+    pub fn get_version() -> Result<String, Error> {
+        match fetch_unchecked(&IpcCommand::GetVersion)? {
+            IpcResponse::Version(version) => Ok(version),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the inotify watchers in use, the system limit and a warning if close to it
+    pub fn get_descriptor_count() -> Result<(usize, usize, Option<String>), Error> {
+        match fetch(&IpcCommand::GetDescriptorCount)? {
+            IpcResponse::DescriptorCount {
+                current,
+                max,
+                warning,
+            } => Ok((current, max, warning)),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Get the path, child count, children and parent path of the directory watched by
+    /// a raw watch descriptor
+    #[allow(clippy::type_complexity)]
+    pub fn get_index_for_descriptor(
+        id: u64,
+    ) -> Result<(PathBuf, usize, Vec<IndexEntry>, Option<PathBuf>), Error> {
+        match fetch(&IpcCommand::GetIndexForDescriptor(id))? {
+            IpcResponse::DirectoryInfo {
+                path,
+                child_count,
+                children,
+                parent_path,
+            } => Ok((path, child_count, children, parent_path)),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn get_pid() -> Result<u32, Error> {
+        match fetch(&IpcCommand::GetPid)? {
+            IpcResponse::Pid(pid) => Ok(pid),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn get_stats() -> Result<IndexStatistics, Error> {
+        match fetch(&IpcCommand::Stats)? {
+            IpcResponse::Stats(stats) => Ok(stats),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn add_ignore_pattern(pattern: String) -> Result<(), Error> {
+        match fetch(&IpcCommand::AddIgnorePattern(pattern))? {
+            IpcResponse::Success => Ok(()),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn remove_ignore_pattern(pattern: String) -> Result<(), Error> {
+        match fetch(&IpcCommand::RemoveIgnorePattern(pattern))? {
+            IpcResponse::Success => Ok(()),
+            IpcResponse::NotFound => Err(Error::NotFound),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    pub fn list_ignore_patterns() -> Result<PatternsInfo, Error> {
+        match fetch(&IpcCommand::ListIgnorePatterns)? {
+            IpcResponse::Patterns(patterns) => Ok(patterns),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Check if a pattern matches a path, returning the result and the `Debug` output of the
+    /// compiled pattern
+    pub fn test_ignore_pattern(pattern: String, path: PathBuf) -> Result<(bool, String), Error> {
+        match fetch(&IpcCommand::TestIgnorePattern(pattern, path))? {
+            IpcResponse::TestResult {
+                matches,
+                pattern_debug,
+            } => Ok((matches, pattern_debug)),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Send a command tagged with an ID, returning the reply to it. Acknowledgements and
+    /// progress sent before the result are skipped
+    pub fn fetch_request(request: &IpcRequest) -> Result<IpcReply, Error> {
+        check_version()?;
+
+        let mut stream =
+            UnixStream::connect(env::var("SOCKET_PATH").unwrap_or(DEFAULT_SOCKET.to_string()))?;
+
+        let mut buf = serde_json::to_vec(request).unwrap();
+        buf.push(0x0);
+        stream.write_all(&buf)?;
+
+        buf.clear();
+        stream.read_to_end(&mut buf)?;
+
+        let frame = buf
+            .split(|byte| *byte == 0x0)
+            .rfind(|frame| !frame.is_empty())
+            .ok_or(Error::Unknown)?;
+        let reply: IpcReply = serde_json::from_slice(frame)?;
+
+        if reply.id == request.id {
+            Ok(reply)
+        } else {
+            Err(Error::Unknown)
+        }
+    }
+
+    /// Reindex everything, returning any warnings once done
+    pub fn regenerate_index() -> Result<Vec<String>, Error> {
+        regenerate_index_with_progress(|_, _| ())
+    }
+
+    /// Reindex everything, calling `progress` with the amount of crawled and total WatchDirs
+    pub fn regenerate_index_with_progress(
+        mut progress: impl FnMut(usize, Option<usize>),
+    ) -> Result<Vec<String>, Error> {
+        check_version()?;
+
+        let mut stream =
+            UnixStream::connect(env::var("SOCKET_PATH").unwrap_or(DEFAULT_SOCKET.to_string()))?;
+
+        let mut buf = serde_json::to_vec(&IpcCommand::FullIndex).unwrap();
+        buf.push(0x0);
+        stream.write_all(&buf)?;
+
+        // Every progress frame is followed by a null byte, the result is not
+        let mut response = None;
+        for frame in BufReader::new(stream).split(0x0) {
+            match serde_json::from_slice(&frame?)? {
+                IpcResponse::Progress { current, total } => progress(current, total),
+                frame => {
+                    response = Some(frame);
+                    break;
+                }
+            }
+        }
+
+        match response {
+            Some(IpcResponse::Success) => Ok(Vec::new()),
+            Some(IpcResponse::Warning(warnings)) => Ok(warnings),
+            Some(IpcResponse::Busy) => Err(Error::Busy),
+            Some(IpcResponse::Error(why)) => Err(Error::Remote(why)),
+            // The daemon closed the connection before sending the result
+            None => Err(Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof))),
             _ => Err(Error::Unknown),
         }
     }
@@ -108,10 +1080,26 @@ pub mod util {
         }
     }
 
-    pub fn reload_config() -> Result<(), Error> {
-        match fetch(&IpcCommand::Reload)? {
+    /// Ask the daemon to quit after its running operations, returning once the shutdown started
+    pub fn graceful_shutdown() -> Result<(), Error> {
+        match fetch(&IpcCommand::GracefulShutdown)? {
             IpcResponse::Success => Ok(()),
             _ => Err(Error::Unknown),
         }
     }
+
+    pub fn reload_config() -> Result<ReloadResult, Error> {
+        match fetch(&IpcCommand::Reload)? {
+            IpcResponse::ReloadResult(result) => Ok(result),
+            _ => Err(Error::Unknown),
+        }
+    }
+
+    /// Reload the config without the debounce, for when the config is known to be fully written
+    pub fn force_reload_config() -> Result<ReloadResult, Error> {
+        match fetch(&IpcCommand::ForceReload)? {
+            IpcResponse::ReloadResult(result) => Ok(result),
+            _ => Err(Error::Unknown),
+        }
+    }
 }
@@ -0,0 +1,77 @@
+use std::{
+    fmt::Display,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use crate::Config;
+
+/// Config file names, in order of preference
+const CONFIG_FILES: &[&str] = &["kidex.ron", "kidex.toml", "kidex.json"];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Ron(ron::error::SpannedError),
+    Toml(toml::de::Error),
+    Json(serde_json::Error),
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(value: io::Error) -> Self {
+        Self::Io(value)
+    }
+}
+
+impl From<ron::error::SpannedError> for ConfigError {
+    fn from(value: ron::error::SpannedError) -> Self {
+        Self::Ron(value)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(value: toml::de::Error) -> Self {
+        Self::Toml(value)
+    }
+}
+
+impl From<serde_json::Error> for ConfigError {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Json(value)
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(why) => write!(f, "Failed to read config file: {}", why),
+            ConfigError::Ron(why) => write!(f, "Invalid RON config: {}", why),
+            ConfigError::Toml(why) => write!(f, "Invalid TOML config: {}", why),
+            ConfigError::Json(why) => write!(f, "Invalid JSON config: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Load the config file, choosing the format based on the file extension
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(path)?;
+
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Ok(toml::from_str(&content)?),
+            Some("json") => Ok(serde_json::from_str(&content)?),
+            _ => Ok(ron::from_str(&content)?),
+        }
+    }
+
+    /// Find the config file in the config directory, defaulting to `kidex.ron`
+    pub fn find(config_dir: &Path) -> PathBuf {
+        CONFIG_FILES
+            .iter()
+            .map(|name| config_dir.join(name))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| config_dir.join(CONFIG_FILES[0]))
+    }
+}
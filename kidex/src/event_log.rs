@@ -0,0 +1,112 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime},
+};
+
+use kidex_common::EventKind;
+use serde::Serialize;
+
+/// Amount of buffered events that triggers a flush
+const FLUSH_EVENTS: usize = 100;
+/// Longest time an event stays buffered
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A single line in the event log
+#[derive(Serialize)]
+struct LoggedEvent<'a> {
+    timestamp: String,
+    kind: &'a str,
+    path: &'a str,
+}
+
+/// Append-only log of the file system events seen by the daemon
+pub struct EventLog {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// Size of the log file to rotate at, in bytes
+    max_size: Option<u64>,
+    size: u64,
+    pending: usize,
+    last_flush: Instant,
+}
+
+impl EventLog {
+    pub fn open(path: &Path, max_mb: Option<u64>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            size: file.metadata()?.len(),
+            writer: BufWriter::new(file),
+            max_size: max_mb.map(|mb| mb * 1024 * 1024),
+            pending: 0,
+            last_flush: Instant::now(),
+        })
+    }
+
+    /// Buffer an event to be written to the log
+    pub fn log(&mut self, kind: EventKind, path: &str) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&LoggedEvent {
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+            kind: match kind {
+                EventKind::Created => "created",
+                EventKind::Deleted => "deleted",
+                EventKind::MovedFrom => "moved_from",
+                EventKind::MovedTo => "moved_to",
+                EventKind::DaemonRestarted => "daemon_restarted",
+                EventKind::Lagged => "lagged",
+            },
+            path,
+        })?;
+        line.push(b'\n');
+
+        if self
+            .max_size
+            .is_some_and(|max_size| self.size + line.len() as u64 > max_size)
+        {
+            self.rotate()?;
+        }
+
+        self.writer.write_all(&line)?;
+        self.size += line.len() as u64;
+        self.pending += 1;
+
+        Ok(())
+    }
+
+    /// Flush the buffered events if there are enough of them or they have waited long enough
+    pub fn flush_if_due(&mut self) -> io::Result<()> {
+        if self.pending >= FLUSH_EVENTS
+            || (self.pending > 0 && self.last_flush.elapsed() >= FLUSH_INTERVAL)
+        {
+            self.writer.flush()?;
+            self.pending = 0;
+            self.last_flush = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    /// Move the current log to `<path>.1` and start a new one, replacing any earlier rotated log
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".1");
+        fs::rename(&self.path, rotated)?;
+
+        self.writer = BufWriter::new(
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)?,
+        );
+        self.size = 0;
+        self.pending = 0;
+        self.last_flush = Instant::now();
+
+        Ok(())
+    }
+}
@@ -1,29 +1,73 @@
 use std::{
-    collections::HashMap,
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, VecDeque},
     ffi::OsStr,
-    fs::{self, File},
-    io,
-    path::PathBuf,
-    sync::Arc,
+    fs, io, mem,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
 };
 
-use inotify::{Event, Inotify, WatchDescriptor, WatchMask};
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use inotify::{EventMask, Inotify, WatchMask, Watches};
+use kidex_common::{DeletedEntry, IndexChange, IndexEntry, TreeNode, WatchDirStats};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
 
-use crate::{ChildIndex, Config, DirectoryIndex, WatchDir};
+use crate::{ChildIndex, Config, Descriptor, DirectoryIndex, WatchDir};
 
 /// The main index struct
 pub struct Index {
-    pub inner: HashMap<WatchDescriptor, DirectoryIndex>,
-    /// The mask used for the watchers
+    pub inner: HashMap<Descriptor, DirectoryIndex>,
+    /// The default mask used for the watchers
     mask: WatchMask,
+    /// Recently created files and directories, newest last
+    recent: VecDeque<(PathBuf, SystemTime)>,
+    /// Recently deleted files and directories, newest last
+    deleted: VecDeque<(PathBuf, SystemTime)>,
+    /// Incremented on every change to the index
+    generation: u64,
+    /// Recent changes along with the generation they produced, oldest first
+    changes: VecDeque<(u64, IndexChange)>,
+    /// The oldest generation `changes` can produce a complete diff from
+    changes_base: u64,
+    /// Indexed files by lowercase extension along with their WatchDir roots, for lookups
+    /// without scanning the whole index
+    extensions: HashMap<String, HashMap<PathBuf, Option<PathBuf>>>,
 }
 
+/// A directory in a dumped index
+#[derive(Serialize, Deserialize)]
+struct DumpedDir {
+    /// Full path of the directory
+    path: PathBuf,
+    children: Vec<(PathBuf, DumpedChild)>,
+}
+
+#[derive(Serialize, Deserialize)]
+enum DumpedChild {
+    File,
+    Directory,
+    Symlink(PathBuf),
+}
+
+/// Amount of recently created entries to remember
+const RECENT_CAPACITY: usize = 1000;
+/// Amount of changes to remember for incremental syncing
+const CHANGES_CAPACITY: usize = 10000;
+
+/// The number of the next directory of a polled WatchDir
+static NEXT_POLLED: AtomicU64 = AtomicU64::new(0);
+
 pub trait GetPath {
-    fn get_path(&self, desc: &WatchDescriptor) -> PathBuf;
+    fn get_path(&self, desc: &Descriptor) -> PathBuf;
 }
 
-impl GetPath for HashMap<WatchDescriptor, DirectoryIndex> {
-    fn get_path(&self, desc: &WatchDescriptor) -> PathBuf {
+impl GetPath for HashMap<Descriptor, DirectoryIndex> {
+    fn get_path(&self, desc: &Descriptor) -> PathBuf {
         let mut desc = Some(desc);
 
         let mut paths = Vec::new();
@@ -55,49 +99,71 @@ impl Index {
         Self {
             inner: HashMap::new(),
             mask: WatchMask::MOVE | WatchMask::CREATE | WatchMask::DELETE,
+            recent: VecDeque::new(),
+            deleted: VecDeque::new(),
+            generation: 0,
+            changes: VecDeque::new(),
+            changes_base: 0,
+            extensions: HashMap::new(),
         }
     }
 
-    /// Index creation for events where a file is "created"
-    pub fn create_index(&mut self, inotify: &mut Inotify, path: &PathBuf, event: &Event<&OsStr>) {
+    /// Index creation for events where a file is "created" in the directory `parent`
+    pub fn create_index(&mut self, inotify: &mut Inotify, parent: &Descriptor, path: &Path) {
         let full_path = self
             .inner
-            .get_path(&event.wd)
+            .get_path(parent)
             .iter()
             .chain(path.iter())
             .collect::<PathBuf>();
 
         if self
             .inner
-            .get(&event.wd)
+            .get(parent)
             .unwrap()
             .watch_dir
-            .ignored
-            .iter()
-            .any(|pat| pat.matches(&full_path.as_os_str().to_string_lossy()))
+            .is_ignored(&full_path.as_os_str().to_string_lossy())
         {
             return;
         }
 
-        let file = match File::open(full_path) {
-            Ok(file) => file,
+        // Not following symlinks, they are indexed as they are
+        let metadata = match fs::symlink_metadata(&full_path) {
+            Ok(metadata) => metadata,
             Err(why) => {
-                log::error!("Failed to open file: {}", why);
+                log::error!("Failed to read file metadata: {}", why);
                 return;
             }
         };
-        let child = if file.metadata().unwrap().file_type().is_dir() {
+        let child = if metadata.file_type().is_symlink() {
+            match fs::read_link(&full_path) {
+                Ok(target) => ChildIndex::Symlink { target },
+                Err(why) => {
+                    log::error!("Failed to read symlink: {}", why);
+                    return;
+                }
+            }
+        } else if metadata.file_type().is_dir() {
+            if self.inner.get(parent).unwrap().watch_dir.skips_dir(path) {
+                return;
+            }
             // If recursion is enabled, recurse through the directories
-            if self.inner.get(&event.wd).unwrap().watch_dir.recurse {
+            if self
+                .inner
+                .get(parent)
+                .unwrap()
+                .watch_dir
+                .recurses_into(path)
+            {
                 log::info!("Directory created, adding watcher!");
                 match self.index_dir(
-                    inotify,
-                    self.inner.get(&event.wd).unwrap().watch_dir.clone(),
+                    inotify.watches(),
+                    self.inner.get(parent).unwrap().watch_dir.clone(),
                     path,
-                    Some(event.wd.clone()),
+                    Some(parent.clone()),
                 ) {
                     Ok(Some((child, index))) => {
-                        self.inner.extend(index.into_iter());
+                        self.inner.extend(index);
                         child
                     }
                     Ok(None) => return,
@@ -109,41 +175,96 @@ impl Index {
             } else {
                 ChildIndex::Directory { descriptor: None }
             }
-        } else if file.metadata().unwrap().file_type().is_file() {
+        } else if metadata.file_type().is_file() {
+            if !self
+                .inner
+                .get(parent)
+                .unwrap()
+                .watch_dir
+                .size_allowed(metadata.len())
+            {
+                return;
+            }
             ChildIndex::File {}
         } else {
             log::warn!("A non-file and non-directory created!");
             return;
         };
 
+        let descriptor = match &child {
+            ChildIndex::Directory { descriptor } => descriptor.clone(),
+            ChildIndex::File {} | ChildIndex::Symlink { .. } => None,
+        };
+        let entry = child_entry(full_path.clone(), &child, self.root_path(parent));
+
         self.inner
-            .get_mut(&event.wd)
+            .get_mut(parent)
             .unwrap()
             .children
-            .insert(path.clone(), child);
+            .insert(path.to_path_buf(), child);
+
+        self.record_change(IndexChange::Added(entry));
+        if let Some(descriptor) = descriptor {
+            for entry in self.iter_dir(descriptor).collect::<Vec<_>>() {
+                self.record_change(IndexChange::Added(entry));
+            }
+        }
+
+        // Remember the creation, moving recreated entries to the front
+        self.recent.retain(|(path, _)| *path != full_path);
+        if self.recent.len() >= RECENT_CAPACITY {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((full_path, SystemTime::now()));
     }
 
-    /// Recursively remove indexed directory/file and remove all watchers
-    pub fn remove_index(&mut self, inotify: &mut Inotify, path: &PathBuf, event: &Event<&OsStr>) {
-        match self.inner.get_mut(&event.wd).unwrap().children.remove(path) {
+    /// Recursively remove indexed directory/file from the directory `parent` and remove all
+    /// watchers, `mask` telling deletions apart from moves
+    pub fn remove_index(
+        &mut self,
+        inotify: &mut Inotify,
+        parent: &Descriptor,
+        path: &Path,
+        mask: EventMask,
+    ) {
+        let full_path = self
+            .inner
+            .get_path(parent)
+            .iter()
+            .chain(path.iter())
+            .collect::<PathBuf>();
+
+        match self.inner.get_mut(parent).unwrap().children.remove(path) {
             Some(child) => {
+                // Moves also remove entries, but nothing was deleted
+                if mask.contains(EventMask::DELETE) {
+                    if self.deleted.len() >= RECENT_CAPACITY {
+                        self.deleted.pop_front();
+                    }
+                    self.deleted
+                        .push_back((full_path.clone(), SystemTime::now()));
+                }
+                self.record_change(IndexChange::Removed(full_path));
                 if let ChildIndex::Directory {
                     descriptor: Some(descriptor),
                 } = child
                 {
+                    for entry in self.iter_dir(descriptor.clone()).collect::<Vec<_>>() {
+                        self.record_change(IndexChange::Removed(entry.path));
+                    }
                     for (desc, dir) in self.traverse(descriptor).into_iter() {
                         log::trace!("Deleted subdir {}", dir.path.display());
 
                         // Delete current descriptor watcher and delete it from the index
                         assert!(self.inner.remove(&desc).is_some());
-                        if let Err(why) = inotify.rm_watch(desc) {
+                        if let Err(why) = unwatch(inotify, &desc) {
                             log::error!("Failed to remove watcher: {}", why);
                         }
                     }
                 }
                 assert!(self
                     .inner
-                    .get_mut(&event.wd)
+                    .get_mut(parent)
                     .unwrap()
                     .children
                     .remove(path)
@@ -158,27 +279,722 @@ impl Index {
         }
     }
 
-    /// Return everything under the selected directory
-    pub fn traverse(&self, desc: WatchDescriptor) -> HashMap<WatchDescriptor, DirectoryIndex> {
+    /// Remember a change for incremental syncing
+    fn record_change(&mut self, change: IndexChange) {
+        // Every individual change passes through here, keeping the extensions up to date
+        match &change {
+            IndexChange::Added(entry) if !entry.directory && !entry.symlink => {
+                if let Some(ext) = extension_key(&entry.path) {
+                    self.extensions
+                        .entry(ext)
+                        .or_default()
+                        .insert(entry.path.clone(), entry.watch_dir_root.clone());
+                }
+            }
+            IndexChange::Removed(path) => {
+                if let Some(ext) = extension_key(path) {
+                    if let Some(paths) = self.extensions.get_mut(&ext) {
+                        paths.remove(path);
+                        if paths.is_empty() {
+                            self.extensions.remove(&ext);
+                        }
+                    }
+                }
+            }
+            IndexChange::Added(_) => (),
+        }
+
+        self.generation += 1;
+        if self.changes.len() >= CHANGES_CAPACITY {
+            if let Some((generation, _)) = self.changes.pop_front() {
+                self.changes_base = generation;
+            }
+        }
+        self.changes.push_back((self.generation, change));
+    }
+
+    /// Recollect the extensions after changes made without `record_change`
+    fn rebuild_extensions(&mut self) {
+        let mut extensions = HashMap::<String, HashMap<_, _>>::new();
+        for entry in self
+            .iter()
+            .filter(|entry| !entry.directory && !entry.symlink)
+        {
+            if let Some(ext) = extension_key(&entry.path) {
+                extensions
+                    .entry(ext)
+                    .or_default()
+                    .insert(entry.path, entry.watch_dir_root);
+            }
+        }
+        self.extensions = extensions;
+    }
+
+    /// Indexed files with the given extension, ignoring case and a leading `.`
+    pub fn by_extension(&self, ext: &str) -> Vec<IndexEntry> {
+        let ext = ext.trim_start_matches('.').to_lowercase();
+
+        self.extensions
+            .get(&ext)
+            .into_iter()
+            .flatten()
+            .map(|(path, watch_dir_root)| IndexEntry {
+                depth: depth_below(path, watch_dir_root.as_deref()),
+                path: path.clone(),
+                directory: false,
+                symlink: false,
+                symlink_target: None,
+                watch_dir_root: watch_dir_root.clone(),
+            })
+            .collect()
+    }
+
+    /// Every indexed file extension along with the amount of files having it, most common
+    /// first. Only files under `root` and accepted by `keep` are counted
+    pub fn extension_counts(
+        &self,
+        root: Option<&Path>,
+        keep: impl Fn(&Path) -> bool,
+    ) -> Vec<(String, usize)> {
+        let mut counts = self
+            .extensions
+            .iter()
+            .map(|(ext, paths)| {
+                let count = paths
+                    .keys()
+                    .filter(|path| root.is_none_or(|root| path.starts_with(root)) && keep(path))
+                    .count();
+                (ext.clone(), count)
+            })
+            .filter(|(_, count)| *count > 0)
+            .collect::<Vec<_>>();
+        counts.sort_by(|(a_ext, a_count), (b_ext, b_count)| {
+            b_count.cmp(a_count).then_with(|| a_ext.cmp(b_ext))
+        });
+
+        counts
+    }
+
+    /// Forget the remembered changes, for changes too large to track individually
+    pub fn reset_changes(&mut self) {
+        self.generation += 1;
+        self.changes.clear();
+        self.changes_base = self.generation;
+    }
+
+    /// The current generation of the index
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Changes made after the given generation, `None` if they are no longer known
+    pub fn changes_since(&self, generation: u64) -> Option<Vec<IndexChange>> {
+        if generation < self.changes_base || generation > self.generation {
+            return None;
+        }
+
+        Some(
+            self.changes
+                .iter()
+                .filter(|(change_generation, _)| *change_generation > generation)
+                .map(|(_, change)| change.clone())
+                .collect(),
+        )
+    }
+
+    /// Index a file or directory by its full path, as if it had just been created.
+    /// Fails with `NotFound` if nothing got indexed, e.g. for missing or ignored paths
+    pub fn add_entry(&mut self, inotify: &mut Inotify, full_path: &Path) -> io::Result<()> {
+        let (parent, name) = self.parent_of(full_path)?;
+        let generation = self.generation;
+        self.create_index(inotify, &parent, Path::new(name));
+
+        // Every change to the index goes through `record_change`
+        if self.generation == generation {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        Ok(())
+    }
+
+    /// Un-index a file or directory by its full path, as if it had just been deleted.
+    /// Fails with `NotFound` if the path is not indexed
+    pub fn remove_entry(&mut self, inotify: &mut Inotify, full_path: &Path) -> io::Result<()> {
+        let (parent, name) = self.parent_of(full_path)?;
+        if !self.inner[&parent].children.contains_key(Path::new(name)) {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        self.remove_index(inotify, &parent, Path::new(name), EventMask::DELETE);
+
+        Ok(())
+    }
+
+    /// Move an indexed file or directory to a new full path without recrawling it,
+    /// for renames whose inotify events were missed
+    pub fn rename_entry(&mut self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_parent, from_name) = self.parent_of(from)?;
+        let (to_parent, to_name) = self.parent_of(to)?;
+
+        if !self.inner[&from_parent]
+            .children
+            .contains_key(Path::new(from_name))
+        {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+        if self.inner[&to_parent]
+            .children
+            .contains_key(Path::new(to_name))
+        {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        // A directory cannot be moved inside of itself
+        if to.starts_with(from) {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+
+        // Remember the old paths of the moved entries before they change
+        let removed = match &self.inner[&from_parent].children[Path::new(from_name)] {
+            ChildIndex::Directory {
+                descriptor: Some(descriptor),
+            } => self
+                .iter_dir(descriptor.clone())
+                .map(|entry| entry.path)
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let child = self
+            .inner
+            .get_mut(&from_parent)
+            .unwrap()
+            .children
+            .remove(Path::new(from_name))
+            .unwrap();
+        // A watched directory keeps its descriptor, as the watch follows the inode
+        if let ChildIndex::Directory {
+            descriptor: Some(descriptor),
+        } = &child
+        {
+            let dir = self.inner.get_mut(descriptor).unwrap();
+            dir.path = PathBuf::from(to_name);
+            dir.parent = Some(to_parent.clone());
+        }
+        let descriptor = match &child {
+            ChildIndex::Directory { descriptor } => descriptor.clone(),
+            ChildIndex::File {} | ChildIndex::Symlink { .. } => None,
+        };
+        let entry = child_entry(to.to_path_buf(), &child, self.root_path(&to_parent));
+
+        self.inner
+            .get_mut(&to_parent)
+            .unwrap()
+            .children
+            .insert(PathBuf::from(to_name), child);
+
+        self.record_change(IndexChange::Removed(from.to_path_buf()));
+        for path in removed {
+            self.record_change(IndexChange::Removed(path));
+        }
+        self.record_change(IndexChange::Added(entry));
+        if let Some(descriptor) = descriptor {
+            for entry in self.iter_dir(descriptor).collect::<Vec<_>>() {
+                self.record_change(IndexChange::Added(entry));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The descriptor of the indexed parent directory of a path, along with the file name,
+    /// as inotify would report them in an event
+    fn parent_of<'a>(&self, full_path: &'a Path) -> io::Result<(Descriptor, &'a OsStr)> {
+        let (parent, name) = match (full_path.parent(), full_path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            _ => return Err(io::Error::from(io::ErrorKind::InvalidInput)),
+        };
+        let desc = self
+            .descriptor_for_path(parent)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+        Ok((desc, name))
+    }
+
+    /// Iterate over every indexed file and directory, yielding entries with fully resolved paths
+    pub fn iter(&self) -> impl Iterator<Item = IndexEntry> + '_ {
+        self.inner
+            .iter()
+            .flat_map(|(desc, dir)| self.dir_entries(desc, dir))
+    }
+
+    /// Fully resolved paths of every indexed file and directory
+    pub fn watched_paths(&self) -> Vec<PathBuf> {
+        self.iter().map(|entry| entry.path).collect()
+    }
+
+    /// Amount of indexed files and directories, under the selected directory if one is given,
+    /// counted without resolving any paths
+    pub fn entry_count(&self, desc: Option<Descriptor>) -> usize {
+        let desc = match desc {
+            Some(desc) => desc,
+            None => return self.inner.values().map(|dir| dir.children.len()).sum(),
+        };
+
         let mut queue = vec![desc];
+        let mut count = 0;
+        while let Some(desc) = queue.pop() {
+            let dir = self.inner.get(&desc).unwrap();
+            count += dir.children.len();
+            queue.extend(dir.children.values().filter_map(|child| match child {
+                ChildIndex::Directory {
+                    descriptor: Some(descriptor),
+                } => Some(descriptor.clone()),
+                _ => None,
+            }));
+        }
 
-        let mut slice = HashMap::new();
+        count
+    }
 
-        while !queue.is_empty() {
-            let desc = queue.pop().unwrap();
+    /// Iterate over every indexed file and directory under the selected directory
+    pub fn iter_dir(&self, desc: Descriptor) -> impl Iterator<Item = IndexEntry> + '_ {
+        self.traverse(desc).into_keys().flat_map(|desc| {
             let dir = self.inner.get(&desc).unwrap();
+            self.dir_entries(&desc, dir).collect::<Vec<_>>()
+        })
+    }
 
-            // If there are subdirectories, add them to the queue
-            queue.extend(
-                dir.children
-                    .iter()
-                    .filter_map(|(_path, child)| match child {
+    /// Entries for the direct children of a single directory
+    pub fn dir_entries<'a>(
+        &self,
+        desc: &Descriptor,
+        dir: &'a DirectoryIndex,
+    ) -> impl Iterator<Item = IndexEntry> + 'a {
+        let parent_path = self.inner.get_path(desc);
+        let root = self.root_path(desc);
+        dir.children.iter().map(move |(path, child)| {
+            let path = parent_path.iter().chain(path.iter()).collect::<PathBuf>();
+            child_entry(path, child, root.clone())
+        })
+    }
+
+    /// Path of the root directory above an indexed directory
+    fn root_path(&self, desc: &Descriptor) -> Option<PathBuf> {
+        let mut dir = self.inner.get(desc)?;
+        while let Some(parent) = &dir.parent {
+            dir = self.inner.get(parent)?;
+        }
+
+        Some(dir.path.clone())
+    }
+
+    /// The indexed file or directory at exactly this full path
+    pub fn find_by_path(&self, path: &Path) -> Option<IndexEntry> {
+        let desc = self.descriptor_for_path(path.parent()?)?;
+        let child = self
+            .inner
+            .get(&desc)?
+            .children
+            .get(Path::new(path.file_name()?))?;
+
+        Some(child_entry(
+            path.to_path_buf(),
+            child,
+            self.root_path(&desc),
+        ))
+    }
+
+    /// The `limit` entries whose file names are the most similar to `query` by Jaro-Winkler
+    /// similarity, ignoring case. Best matches first, only entries accepted by `keep` are scored
+    pub fn fuzzy_search(
+        &self,
+        query: &str,
+        limit: usize,
+        keep: impl Fn(&Path) -> bool,
+    ) -> Vec<IndexEntry> {
+        let query = query.to_lowercase();
+
+        let mut scored = self
+            .iter()
+            .filter(|entry| keep(&entry.path))
+            .filter_map(|entry| {
+                let name = entry.path.file_name()?.to_string_lossy().to_lowercase();
+                Some((strsim::jaro_winkler(&query, &name), entry))
+            })
+            .collect::<Vec<_>>();
+        scored.sort_by(|(a_score, a), (b_score, b)| {
+            b_score.total_cmp(a_score).then_with(|| a.path.cmp(&b.path))
+        });
+
+        scored
+            .into_iter()
+            .take(limit)
+            .map(|(_, entry)| entry)
+            .collect()
+    }
+
+    /// Entries nested exactly `depth` levels below `root`, or below their WatchDir root if
+    /// none is given, with 1 being direct children. `None` if `root` is not indexed
+    pub fn at_depth(&self, depth: usize, root: Option<&Path>) -> Option<Vec<IndexEntry>> {
+        let entries: Box<dyn Iterator<Item = IndexEntry>> = match root {
+            Some(root) => Box::new(self.iter_dir(self.descriptor_for_path(root)?)),
+            None => Box::new(self.iter()),
+        };
+
+        Some(
+            entries
+                .filter(|entry| match root {
+                    Some(root) => depth_below(&entry.path, Some(root)) == depth,
+                    None => entry.depth == depth,
+                })
+                .collect(),
+        )
+    }
+
+    /// Every indexed file and directory named exactly `name`, found with a lookup per
+    /// directory instead of resolving every path
+    pub fn find_by_name(&self, name: &str) -> Vec<IndexEntry> {
+        self.inner
+            .iter()
+            .filter_map(|(desc, dir)| {
+                let child = dir.children.get(Path::new(name))?;
+                let path = self.inner.get_path(desc).join(name);
+                Some(child_entry(path, child, self.root_path(desc)))
+            })
+            .collect()
+    }
+
+    /// Every indexed symlink, under the selected directory if one is given
+    pub fn symlinks(&self, desc: Option<Descriptor>) -> Vec<IndexEntry> {
+        let entries: Box<dyn Iterator<Item = IndexEntry>> = match desc {
+            Some(desc) => Box::new(self.iter_dir(desc)),
+            None => Box::new(self.iter()),
+        };
+
+        entries.filter(|entry| entry.symlink).collect()
+    }
+
+    /// Watched subdirectories without any indexed children, under the selected directory if
+    /// one is given. WatchDir roots are left out, as they are not entries of the index
+    pub fn empty_dirs(&self, desc: Option<Descriptor>) -> Vec<IndexEntry> {
+        let descs = match desc {
+            Some(desc) => self.traverse(desc).into_keys().collect(),
+            None => self.inner.keys().cloned().collect::<Vec<_>>(),
+        };
+
+        descs
+            .into_iter()
+            .filter(|desc| {
+                let dir = &self.inner[desc];
+                !dir.is_root() && dir.children.is_empty()
+            })
+            .map(|desc| {
+                let path = self.inner.get_path(&desc);
+                let watch_dir_root = self.root_path(&desc);
+                IndexEntry {
+                    depth: depth_below(&path, watch_dir_root.as_deref()),
+                    path,
+                    directory: true,
+                    symlink: false,
+                    symlink_target: None,
+                    watch_dir_root,
+                }
+            })
+            .collect()
+    }
+
+    /// The indexed files, under the selected directory if one is given, leaving out symlinks
+    /// and the files `keep` rejects
+    pub fn files(&self, desc: Option<Descriptor>, keep: impl Fn(&Path) -> bool) -> Vec<IndexEntry> {
+        let entries: Box<dyn Iterator<Item = IndexEntry>> = match desc {
+            Some(desc) => Box::new(self.iter_dir(desc)),
+            None => Box::new(self.iter()),
+        };
+
+        entries
+            .filter(|entry| !entry.directory && !entry.symlink && keep(&entry.path))
+            .collect()
+    }
+
+    /// The most recently created entries that are still indexed, newest first
+    pub fn recently_created(&self) -> impl Iterator<Item = IndexEntry> + '_ {
+        self.recent
+            .iter()
+            .rev()
+            .filter_map(|(path, _)| self.find_by_path(path))
+    }
+
+    /// The ancestor directories of an indexed entry, from its WatchDir root down to its
+    /// parent. `None` if the entry is not indexed
+    pub fn parent_chain(&self, path: &Path) -> Option<Vec<IndexEntry>> {
+        let mut desc = self.descriptor_for_path(path.parent()?)?;
+        self.inner
+            .get(&desc)?
+            .children
+            .get(Path::new(path.file_name()?))?;
+
+        let root = self.root_path(&desc);
+        let mut chain = Vec::new();
+        loop {
+            let path = self.inner.get_path(&desc);
+            chain.push(IndexEntry {
+                depth: depth_below(&path, root.as_deref()),
+                path,
+                directory: true,
+                symlink: false,
+                symlink_target: None,
+                watch_dir_root: root.clone(),
+            });
+            match &self.inner.get(&desc)?.parent {
+                Some(parent) => desc = parent.clone(),
+                None => break,
+            }
+        }
+        chain.reverse();
+
+        Some(chain)
+    }
+
+    /// The most recently deleted entries, newest first
+    pub fn recently_deleted(&self) -> impl Iterator<Item = DeletedEntry> + '_ {
+        self.deleted
+            .iter()
+            .rev()
+            .map(|(path, deleted_at)| DeletedEntry {
+                path: path.clone(),
+                deleted_at: *deleted_at,
+            })
+    }
+
+    /// Detailed statistics about an indexed directory along with the non-directory entries under
+    /// it, `None` if it is not indexed. `oldest_indexed_entry` is left for the caller to fill in
+    /// with `oldest_modified` once the index is no longer locked
+    pub fn watch_dir_stats(&self, path: &Path) -> Option<(WatchDirStats, Vec<PathBuf>)> {
+        let desc = self.descriptor_for_path(path)?;
+        let dir = self.inner.get(&desc)?;
+
+        let entries = self
+            .iter_dir(desc.clone())
+            .filter(|entry| !entry.directory)
+            .map(|entry| entry.path)
+            .collect();
+
+        let stats = WatchDirStats {
+            path: path.to_path_buf(),
+            file_count: dir.file_count(self),
+            dir_count: dir.dir_count(self),
+            total_watch_descriptors: self.traverse(desc).len(),
+            oldest_indexed_entry: None,
+            recently_indexed_files: self
+                .recently_created()
+                .filter(|entry| !entry.directory && entry.path.starts_with(path))
+                .map(|entry| entry.path)
+                .take(5)
+                .collect(),
+        };
+
+        Some((stats, entries))
+    }
+
+    /// Paths of the root directories that were actually indexed
+    pub fn top_level_dirs(&self) -> Vec<PathBuf> {
+        self.inner
+            .values()
+            .filter(|dir| dir.is_root())
+            .map(|dir| dir.path.clone())
+            .collect()
+    }
+
+    /// Build the tree of an indexed directory, leaving out the paths `keep` rejects
+    pub fn tree(&self, desc: &Descriptor, name: String, keep: &impl Fn(&Path) -> bool) -> TreeNode {
+        let path = self.inner.get_path(desc);
+
+        let mut children = self.inner[desc]
+            .children
+            .iter()
+            .filter(|(child_name, _)| keep(&path.join(child_name)))
+            .map(|(child_name, child)| {
+                let child_name = child_name.to_string_lossy().into_owned();
+                match child {
+                    ChildIndex::Directory {
+                        descriptor: Some(descriptor),
+                    } => self.tree(descriptor, child_name, keep),
+                    _ => TreeNode {
+                        name: child_name,
+                        is_dir: matches!(child, ChildIndex::Directory { .. }),
+                        children: Vec::new(),
+                    },
+                }
+            })
+            .collect::<Vec<_>>();
+        children.sort_by(|a, b| a.name.cmp(&b.name));
+
+        TreeNode {
+            name,
+            is_dir: true,
+            children,
+        }
+    }
+
+    /// The WatchDir whose root contains a path, the innermost one if roots are nested
+    pub fn watch_dir_for_path(&self, path: &Path) -> Option<Arc<WatchDir>> {
+        self.inner
+            .iter()
+            .filter(|(desc, dir)| dir.is_root() && path.starts_with(self.inner.get_path(desc)))
+            .max_by_key(|(_, dir)| dir.path.components().count())
+            .map(|(_, dir)| dir.watch_dir.clone())
+    }
+
+    /// Find the watch descriptor of an indexed directory by its full path
+    pub fn descriptor_for_path(&self, path: &Path) -> Option<Descriptor> {
+        self.inner
+            .iter()
+            .filter(|(_, dir)| dir.is_root())
+            .find_map(|(desc, root)| {
+                let mut desc = desc;
+
+                // Walk down the tree one component at a time
+                for component in path.strip_prefix(&root.path).ok()?.iter() {
+                    match self.inner.get(desc)?.children.get(Path::new(component))? {
                         ChildIndex::Directory {
                             descriptor: Some(descriptor),
-                        } => Some(descriptor.clone()),
-                        _ => None,
-                    }),
+                        } => desc = descriptor,
+                        _ => return None,
+                    }
+                }
+
+                Some(desc.clone())
+            })
+    }
+
+    /// Find the descriptor of an indexed directory by its raw integer value
+    pub fn descriptor_for_id(&self, id: u64) -> Option<Descriptor> {
+        self.inner
+            .keys()
+            .find(|desc| {
+                desc.watch()
+                    .is_some_and(|wd| wd.get_watch_descriptor_id() as u64 == id)
+            })
+            .cloned()
+    }
+
+    /// Replace the inotify mask of an indexed directory and its subdirectories,
+    /// returning false if the directory is not indexed
+    pub fn set_watch_mask(&mut self, inotify: &mut Inotify, path: &Path, mask: WatchMask) -> bool {
+        let desc = match self.descriptor_for_path(path) {
+            Some(desc) => desc,
+            None => return false,
+        };
+
+        for desc in self.traverse(desc).into_keys() {
+            // Polled directories have no watcher to change
+            let wd = match desc.watch() {
+                Some(wd) => wd,
+                None => continue,
+            };
+            let path = self.inner.get_path(&desc);
+
+            // Adding a watch for an already watched inode replaces its mask
+            match inotify.watches().add(&path, mask) {
+                Ok(new_desc) if new_desc == *wd => self.inner.get_mut(&desc).unwrap().mask = mask,
+                Ok(new_desc) => {
+                    log::warn!("Stale watcher found for {}", path.display());
+                    if let Err(why) = inotify.watches().remove(new_desc) {
+                        log::error!("Failed to remove watcher: {}", why);
+                    }
+                }
+                Err(why) => log::error!("Failed to update watcher: {}", why),
+            }
+        }
+
+        true
+    }
+
+    /// Remove and re-add the inotify watcher of a single indexed directory, moving its
+    /// index over to the new descriptor
+    pub fn rewatch(&mut self, inotify: &mut Inotify, path: &Path) -> io::Result<()> {
+        let desc = self
+            .descriptor_for_path(path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let full_path = self.inner.get_path(&desc);
+        let mask = self.inner[&desc].mask;
+
+        // Polled directories are not watched to begin with
+        let wd = desc
+            .watch()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))?;
+        // The watcher may already be gone along with a remounted device
+        if let Err(why) = inotify.watches().remove(wd.clone()) {
+            log::debug!("Failed to remove watcher: {}", why);
+        }
+        let new_desc = Descriptor::Watch(inotify.watches().add(&full_path, mask)?);
+        if new_desc == desc {
+            return Ok(());
+        }
+
+        let dir = self.inner.remove(&desc).unwrap();
+        if let Some(parent) = dir
+            .parent
+            .as_ref()
+            .and_then(|parent| self.inner.get_mut(parent))
+        {
+            parent.children.insert(
+                dir.path.clone(),
+                ChildIndex::Directory {
+                    descriptor: Some(new_desc.clone()),
+                },
             );
+        }
+        for child in dir.children.values() {
+            if let ChildIndex::Directory {
+                descriptor: Some(child),
+            } = child
+            {
+                if let Some(child) = self.inner.get_mut(child) {
+                    child.parent = Some(new_desc.clone());
+                }
+            }
+        }
+        self.inner.insert(new_desc, dir);
+
+        Ok(())
+    }
+
+    /// Rough estimate of the heap memory used by the index, in bytes
+    pub fn memory_usage_estimate(&self) -> usize {
+        self.inner
+            .values()
+            .map(|dir| {
+                mem::size_of::<Descriptor>()
+                    + mem::size_of::<DirectoryIndex>()
+                    + dir.path.as_os_str().len()
+                    + dir
+                        .children
+                        .keys()
+                        .map(|path| {
+                            mem::size_of::<PathBuf>()
+                                + mem::size_of::<ChildIndex>()
+                                + path.as_os_str().len()
+                        })
+                        .sum::<usize>()
+            })
+            .sum()
+    }
+
+    /// Return everything under the selected directory
+    pub fn traverse(&self, desc: Descriptor) -> HashMap<Descriptor, DirectoryIndex> {
+        let mut queue = vec![desc];
+
+        let mut slice = HashMap::new();
+
+        while let Some(desc) = queue.pop() {
+            let dir = self.inner.get(&desc).unwrap();
+
+            // If there are subdirectories, add them to the queue
+            queue.extend(dir.children.values().filter_map(|child| match child {
+                ChildIndex::Directory {
+                    descriptor: Some(descriptor),
+                } => Some(descriptor.clone()),
+                _ => None,
+            }));
 
             slice.insert(desc, dir.clone());
         }
@@ -189,39 +1005,41 @@ impl Index {
     /// Index everything inside a directory and the directory, and recurse if enabled
     pub fn index_dir(
         &self,
-        inotify: &mut Inotify,
+        mut watches: Watches,
         watch_dir: Arc<WatchDir>,
-        path: &PathBuf,
-        parent: Option<WatchDescriptor>,
-    ) -> io::Result<Option<(ChildIndex, HashMap<WatchDescriptor, DirectoryIndex>)>> {
+        path: &Path,
+        parent: Option<Descriptor>,
+    ) -> io::Result<Option<(ChildIndex, HashMap<Descriptor, DirectoryIndex>)>> {
         let full_path = match &parent {
             Some(parent) => {
                 let mut new_path = self.inner.get_path(parent);
                 new_path.extend(path.iter());
                 new_path
             }
-            None => path.clone(),
+            None => path.to_path_buf(),
         };
 
-        if watch_dir
-            .ignored
-            .iter()
-            .any(|pat| pat.matches(&path.to_string_lossy()))
-        {
+        if watch_dir.is_ignored(&path.to_string_lossy()) {
             return Ok(None);
         }
 
-        let desc = inotify.add_watch(&full_path, self.mask)?;
+        // Subdirectories inherit the mask of their parent
+        let mask = match &parent {
+            Some(parent) => self.inner.get(parent).unwrap().mask,
+            None => watch_dir.watch_events.unwrap_or(self.mask),
+        };
+        let desc = watch(&mut watches, &watch_dir, &full_path, mask)?;
 
         let mut index = HashMap::new();
 
         index.insert(
             desc.clone(),
             DirectoryIndex {
-                path: path.clone(),
+                path: path.to_path_buf(),
                 children: HashMap::new(),
                 watch_dir: watch_dir.clone(),
                 parent,
+                mask,
             },
         );
 
@@ -229,16 +1047,11 @@ impl Index {
             .filter_map(|res| res.ok().map(|entry| (entry, desc.clone())))
             .collect::<Vec<_>>();
 
-        while !queue.is_empty() {
-            let (entry, desc) = queue.pop().unwrap();
+        while let Some((entry, desc)) = queue.pop() {
             let path = entry.path().file_name().map(PathBuf::from).unwrap();
 
             // Ignore files specified with ignore patterns
-            if !watch_dir
-                .ignored
-                .iter()
-                .any(|pat| pat.matches(&path.to_string_lossy()))
-            {
+            if !watch_dir.is_ignored(&path.to_string_lossy()) {
                 let full_path = index
                     .get_path(&desc)
                     .iter()
@@ -252,8 +1065,12 @@ impl Index {
                     }
                 };
 
-                if file_type.is_dir() && watch_dir.recurse {
-                    let new_desc = match inotify.add_watch(&full_path, self.mask) {
+                if file_type.is_dir() && watch_dir.skips_dir(&path) {
+                    continue;
+                }
+
+                if file_type.is_dir() && watch_dir.recurses_into(&path) {
+                    let new_desc = match watch(&mut watches, &watch_dir, &full_path, mask) {
                         Ok(new_desc) => {
                             log::trace!("Indexed subdirectory {}", full_path.display());
                             match fs::read_dir(&full_path) {
@@ -275,6 +1092,7 @@ impl Index {
                                     children: HashMap::new(),
                                     watch_dir: watch_dir.clone(),
                                     parent: Some(desc.clone()),
+                                    mask,
                                 },
                             );
                             Some(new_desc)
@@ -301,11 +1119,30 @@ impl Index {
                         .children
                         .insert(path, ChildIndex::Directory { descriptor: None });
                 } else if file_type.is_file() {
+                    match entry.metadata() {
+                        Ok(metadata) if !watch_dir.size_allowed(metadata.len()) => continue,
+                        Ok(_) => (),
+                        Err(why) => {
+                            log::error!("Failed to read file metadata, skipping: {}", why);
+                            continue;
+                        }
+                    }
                     index
                         .get_mut(&desc)
                         .unwrap()
                         .children
                         .insert(path, ChildIndex::File {});
+                } else if file_type.is_symlink() {
+                    match fs::read_link(&full_path) {
+                        Ok(target) => {
+                            index
+                                .get_mut(&desc)
+                                .unwrap()
+                                .children
+                                .insert(path, ChildIndex::Symlink { target });
+                        }
+                        Err(why) => log::error!("Failed to read symlink, skipping: {}", why),
+                    }
                 }
             }
         }
@@ -318,33 +1155,565 @@ impl Index {
         )))
     }
 
-    /// Completely clear and reindex everything
-    pub fn full_index(&mut self, inotify: &mut Inotify, config: &Config) -> io::Result<()> {
+    /// Drop and recrawl everything under an indexed root directory
+    pub fn reindex_subtree(&mut self, inotify: &mut Inotify, path: &Path) -> io::Result<()> {
+        let (desc, watch_dir) = match self
+            .inner
+            .iter()
+            .find(|(_, dir)| dir.is_root() && dir.path == path)
+        {
+            Some((desc, dir)) => (desc.clone(), dir.watch_dir.clone()),
+            None => return Err(io::Error::from(io::ErrorKind::NotFound)),
+        };
+
+        let mut before = self
+            .iter_dir(desc.clone())
+            .map(|entry| (entry.path.clone(), entry))
+            .collect::<HashMap<_, _>>();
+
+        // Polled subtrees have no watchers, only the index is dropped
+        for desc in self.traverse(desc).into_keys() {
+            self.inner.remove(&desc);
+            if let Err(why) = unwatch(inotify, &desc) {
+                log::error!("Failed to remove watcher: {}", why);
+            }
+        }
+
+        if let Some((_, index)) = self.index_dir(inotify.watches(), watch_dir, path, None)? {
+            self.inner.extend(index);
+        }
+        let after = match self.descriptor_for_path(path) {
+            Some(desc) => self.iter_dir(desc).collect(),
+            None => Vec::new(),
+        };
+
+        // Only record what actually changed, polling mostly finds nothing new. Entries that
+        // changed their type are recorded as removed and added again
+        let added = after
+            .into_iter()
+            .filter(|entry| match before.get(&entry.path) {
+                Some(old)
+                    if old.directory == entry.directory
+                        && old.symlink_target == entry.symlink_target =>
+                {
+                    before.remove(&entry.path);
+                    false
+                }
+                _ => true,
+            })
+            .collect::<Vec<_>>();
+        for path in before.into_keys() {
+            self.record_change(IndexChange::Removed(path));
+        }
+        for entry in added {
+            self.record_change(IndexChange::Added(entry));
+        }
+
+        Ok(())
+    }
+
+    /// Apply the current config of the WatchDir rooted at `path` to its indexed subtree and
+    /// recrawl it, leaving the other roots untouched
+    pub fn update_watch_dir(
+        &mut self,
+        inotify: &mut Inotify,
+        config: &Config,
+        path: &Path,
+    ) -> io::Result<()> {
+        let watch_dir = Self::watch_dirs(config)
+            .into_iter()
+            .find(|watch_dir| Path::new(&watch_dir.path) == path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        let root = self
+            .inner
+            .values_mut()
+            .find(|dir| dir.is_root() && dir.path == path)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+        root.watch_dir = watch_dir;
+
+        self.reindex_subtree(inotify, path)
+    }
+
+    /// Find watchers no longer pointing at their indexed directory, dropping and recrawling
+    /// their subtrees. Returns the paths of the stale directories
+    pub fn validate_watchers(&mut self, inotify: &mut Inotify) -> Vec<PathBuf> {
+        let dirs = self
+            .inner
+            .iter()
+            .map(|(desc, dir)| (desc.clone(), self.inner.get_path(desc), dir.mask))
+            .collect::<Vec<_>>();
+
+        let mut stale = Vec::new();
+
+        for (desc, path, mask) in dirs {
+            // Already dropped as a part of a stale parent
+            if !self.inner.contains_key(&desc) {
+                continue;
+            }
+
+            // Adding a watch for an already watched inode returns the same descriptor,
+            // anything else means the directory was replaced or is gone. Polled directories
+            // are recrawled anyway
+            let wd = match desc.watch() {
+                Some(wd) => wd,
+                None => continue,
+            };
+            match inotify.watches().add(&path, mask) {
+                Ok(new_desc) if new_desc == *wd => continue,
+                Ok(new_desc) => {
+                    if let Err(why) = inotify.watches().remove(new_desc) {
+                        log::error!("Failed to remove watcher: {}", why);
+                    }
+                }
+                Err(_) => (),
+            }
+
+            log::warn!("Stale watcher found for {}", path.display());
+
+            let dir = self.inner.get(&desc).unwrap();
+            let (parent, name, watch_dir) =
+                (dir.parent.clone(), dir.path.clone(), dir.watch_dir.clone());
+
+            for (desc, _) in self.traverse(desc) {
+                self.inner.remove(&desc);
+                if let Err(why) = unwatch(inotify, &desc) {
+                    log::debug!("Failed to remove stale watcher: {}", why);
+                }
+            }
+
+            if let Some(parent) = &parent {
+                self.inner.get_mut(parent).unwrap().children.remove(&name);
+            }
+
+            // Recrawl the directory if it still exists
+            if path.is_dir() {
+                let result = if parent.is_some() {
+                    self.add_entry(inotify, &path)
+                } else {
+                    self.index_dir(inotify.watches(), watch_dir, &path, None)
+                        .map(|index| {
+                            if let Some((_, index)) = index {
+                                self.inner.extend(index);
+                            }
+                        })
+                };
+                if let Err(why) = result {
+                    log::error!("Failed to reindex {}: {}", path.display(), why);
+                }
+            }
+
+            stale.push(path);
+        }
+
+        if !stale.is_empty() {
+            self.reset_changes();
+            self.rebuild_extensions();
+        }
+
+        stale
+    }
+
+    /// Remove indexed entries that no longer exist on the filesystem, for changes that
+    /// inotify did not report. Returns the amount of removed entries
+    pub fn rebuild_stale(&mut self, inotify: &mut Inotify) -> usize {
+        let mut stale = self
+            .watched_paths()
+            .into_iter()
+            // Not following symlinks, broken ones still exist
+            .filter(|path| fs::symlink_metadata(path).is_err())
+            .collect::<Vec<_>>();
+        // Parents first, removing them takes care of their children
+        stale.sort_by_key(|path| path.components().count());
+
+        for path in &stale {
+            // Fails for children of already removed directories
+            let _ = self.remove_entry(inotify, path);
+        }
+
+        stale.len()
+    }
+
+    /// Paths of watched directories that no longer exist, e.g. after an unmount that
+    /// inotify did not report. Parents come before their children
+    pub fn orphaned_paths(&self) -> Vec<PathBuf> {
+        let mut orphans = self
+            .inner
+            .keys()
+            .map(|desc| self.inner.get_path(desc))
+            .filter(|path| !path.exists())
+            .collect::<Vec<_>>();
+        orphans.sort_by_key(|path| path.components().count());
+
+        orphans
+    }
+
+    /// Drop watched directories that no longer exist along with their subtrees,
+    /// returning their paths
+    pub fn clean_orphans(&mut self, inotify: &mut Inotify) -> Vec<PathBuf> {
+        let orphans = self
+            .inner
+            .iter()
+            .map(|(desc, dir)| (desc.clone(), dir, self.inner.get_path(desc)))
+            .filter(|(_, _, path)| !path.exists())
+            .map(|(desc, dir, path)| (desc, dir.parent.clone(), dir.path.clone(), path))
+            .collect::<Vec<_>>();
+
+        for (desc, parent, name, _) in &orphans {
+            // Already dropped as a part of an orphaned parent
+            if !self.inner.contains_key(desc) {
+                continue;
+            }
+
+            for (desc, _) in self.traverse(desc.clone()) {
+                self.inner.remove(&desc);
+                if let Err(why) = unwatch(inotify, &desc) {
+                    log::debug!("Failed to remove orphaned watcher: {}", why);
+                }
+            }
+
+            if let Some(dir) = parent
+                .as_ref()
+                .and_then(|parent| self.inner.get_mut(parent))
+            {
+                dir.children.remove(name);
+            }
+        }
+
+        if !orphans.is_empty() {
+            self.reset_changes();
+            self.rebuild_extensions();
+        }
+
+        let mut orphans = orphans
+            .into_iter()
+            .map(|(_, _, _, path)| path)
+            .collect::<Vec<_>>();
+        orphans.sort_by_key(|path| path.components().count());
+
+        orphans
+    }
+
+    /// Completely clear and reindex everything, returning warnings about skipped directories
+    pub fn full_index(
+        &mut self,
+        inotify: &mut Inotify,
+        config: &Config,
+    ) -> io::Result<Vec<String>> {
+        self.full_index_with_progress(inotify, config, &|_, _| ())
+    }
+
+    /// `full_index`, calling `progress` with the amount of crawled and total WatchDirs
+    /// every time a WatchDir is done
+    pub fn full_index_with_progress(
+        &mut self,
+        inotify: &mut Inotify,
+        config: &Config,
+        progress: &(dyn Fn(usize, usize) + Sync),
+    ) -> io::Result<Vec<String>> {
         log::info!("Starting full index");
 
         self.clear_index(inotify)?;
 
-        for watch_dir in &config.directories {
-            // Extend the WatchDir's ignored list with the global ignored list
-            let mut new_watch_dir = watch_dir.clone();
-            new_watch_dir.ignored.extend(config.ignored.iter().cloned());
+        let watch_dirs = Self::watch_dirs(config);
+
+        let mut warnings = Vec::new();
+
+        for (i, watch_dir) in config.directories.iter().enumerate() {
+            if let Some(covering) = Self::covering_watch_dir(config, i) {
+                let warning = format!(
+                    "Skipped WatchDir {}: already indexed as a part of {}",
+                    watch_dir.path, covering.path
+                );
+                log::warn!("{}", warning);
+                warnings.push(warning);
+            }
+        }
 
-            match self.index_dir(
-                inotify,
-                Arc::new(new_watch_dir),
-                &PathBuf::from(&watch_dir.path),
+        let watches = inotify.watches();
+        let done = AtomicUsize::new(0);
+        let index_watch_dir = |watch_dir: &Arc<WatchDir>| {
+            let result = self.index_dir(
+                watches.clone(),
+                watch_dir.clone(),
+                Path::new(&watch_dir.path),
                 None,
-            ) {
-                Ok(Some((_, index))) => self.inner.extend(index.into_iter()),
+            );
+            progress(done.fetch_add(1, Ordering::SeqCst) + 1, watch_dirs.len());
+            result
+        };
+
+        // Crawl the WatchDirs concurrently if multiple workers are configured
+        let results = match config.concurrent_index_workers {
+            Some(workers) if workers > 1 => rayon::ThreadPoolBuilder::new()
+                .num_threads(workers)
+                .build()
+                .map_err(io::Error::other)?
+                .install(|| watch_dirs.par_iter().map(index_watch_dir).collect()),
+            _ => watch_dirs.iter().map(index_watch_dir).collect::<Vec<_>>(),
+        };
+
+        for (watch_dir, result) in watch_dirs.iter().zip(results) {
+            match result {
+                Ok(Some((_, index))) => self.inner.extend(index),
                 Ok(None) => (),
                 Err(why) => {
                     log::error!("Skipping WatchDir {} due to error: {}", watch_dir.path, why);
+                    warnings.push(format!("Skipped WatchDir {}: {}", watch_dir.path, why));
                     continue;
                 }
             }
         }
 
-        log::info!("Full index done!");
+        if let Some(max) = crate::max_user_watches() {
+            if self.inner.len() * 10 >= max * 9 {
+                let warning = format!(
+                    "Using {} of the {} available inotify watchers",
+                    self.inner.len(),
+                    max
+                );
+                log::warn!("{}", warning);
+                warnings.push(warning);
+            }
+        }
+
+        self.rebuild_extensions();
+
+        log::info!(
+            "Full index done! Indexed roots: {:?}",
+            self.top_level_dirs()
+        );
+
+        Ok(warnings)
+    }
+
+    /// The configured WatchDirs, with the global ignored list included
+    fn watch_dirs(config: &Config) -> Vec<Arc<WatchDir>> {
+        config
+            .directories
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| Self::covering_watch_dir(config, *i).is_none())
+            .map(|(_, watch_dir)| {
+                // Extend the WatchDir's ignored list with the global ignored list
+                let mut new_watch_dir = watch_dir.clone();
+                new_watch_dir.ignored.extend(config.ignored.iter().cloned());
+
+                // Resolve the case sensitivity, lowercasing the patterns to match lowercased paths
+                let case_sensitive = watch_dir
+                    .case_sensitive_ignore
+                    .unwrap_or(config.case_sensitive_ignore);
+                if !case_sensitive {
+                    new_watch_dir.ignored = new_watch_dir
+                        .ignored
+                        .iter()
+                        .map(|pat| pat.to_lowercase())
+                        .collect();
+                    new_watch_dir.recurse_ignore_patterns = watch_dir
+                        .recurse_ignore_patterns
+                        .iter()
+                        .map(|pat| pat.to_lowercase())
+                        .collect();
+                }
+                new_watch_dir.case_sensitive_ignore = Some(case_sensitive);
+                new_watch_dir.ignored_dir_set = watch_dir.ignored_dirs.iter().cloned().collect();
+
+                Arc::new(new_watch_dir)
+            })
+            .collect()
+    }
+
+    /// The WatchDir already covering the one at `index` in the config, either by being the same
+    /// directory or by recursing into it. inotify hands out the same watcher for a directory
+    /// each time it is watched, so indexing it twice would overwrite the first index of it
+    fn covering_watch_dir(config: &Config, index: usize) -> Option<&WatchDir> {
+        // Symlinked paths still lead to the same watchers
+        let resolve = |watch_dir: &WatchDir| {
+            fs::canonicalize(&watch_dir.path).unwrap_or_else(|_| PathBuf::from(&watch_dir.path))
+        };
+        let path = resolve(&config.directories[index]);
+
+        config
+            .directories
+            .iter()
+            .enumerate()
+            .find(|(i, other)| {
+                let other_path = resolve(other);
+                if other_path == path {
+                    // Of two identical WatchDirs, the first one is kept
+                    *i < index
+                } else {
+                    other.recurse && path.starts_with(&other_path)
+                }
+            })
+            .map(|(_, other)| other)
+    }
+
+    /// Serialize the directory structure of the index into a compressed blob
+    pub fn dump(&self) -> io::Result<Vec<u8>> {
+        let roots = self
+            .inner
+            .iter()
+            .filter(|(_, dir)| dir.is_root())
+            .map(|(desc, _)| desc.clone())
+            .collect::<Vec<_>>();
+
+        let mut dirs = Vec::new();
+        for root in roots {
+            let mut queue = VecDeque::from([root]);
+
+            // Breadth first, so that parents always come before their children
+            while let Some(desc) = queue.pop_front() {
+                let dir = self.inner.get(&desc).unwrap();
+                dirs.push(DumpedDir {
+                    path: self.inner.get_path(&desc),
+                    children: dir
+                        .children
+                        .iter()
+                        .map(|(path, child)| {
+                            let child = match child {
+                                ChildIndex::File {} => DumpedChild::File,
+                                ChildIndex::Symlink { target } => {
+                                    DumpedChild::Symlink(target.clone())
+                                }
+                                ChildIndex::Directory { descriptor } => {
+                                    queue.extend(descriptor.clone());
+                                    DumpedChild::Directory
+                                }
+                            };
+                            (path.clone(), child)
+                        })
+                        .collect(),
+                });
+            }
+        }
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        bincode::serialize_into(&mut encoder, &dirs).map_err(io::Error::other)?;
+        encoder.finish()
+    }
+
+    /// Replace the index with one from a blob created by `dump`, adding watchers for it.
+    /// Directories that are no longer configured are left out
+    pub fn load(&mut self, inotify: &mut Inotify, config: &Config, blob: &[u8]) -> io::Result<()> {
+        let dirs: Vec<DumpedDir> =
+            bincode::deserialize_from(GzDecoder::new(blob)).map_err(io::Error::other)?;
+        let watch_dirs = Self::watch_dirs(config);
+
+        // The blob comes from a client, check all of it before touching the current index
+        for dumped in &dirs {
+            let normalized = dumped.path.is_absolute()
+                && dumped.path.components().all(|component| {
+                    matches!(component, Component::RootDir | Component::Normal(_))
+                });
+            if !normalized {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Directory path {} is not normalized", dumped.path.display()),
+                ));
+            }
+            // Children are single names, anything else would point outside of the directory
+            if let Some((name, _)) = dumped.children.iter().find(|(name, _)| {
+                let mut components = name.components();
+                !matches!(
+                    (components.next(), components.next()),
+                    (Some(Component::Normal(_)), None)
+                )
+            }) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid child name {}", name.display()),
+                ));
+            }
+        }
+
+        self.clear_index(inotify)?;
+
+        let mut descriptors = HashMap::<PathBuf, Descriptor>::new();
+
+        for dumped in dirs {
+            let parent = dumped
+                .path
+                .parent()
+                .and_then(|path| descriptors.get(path))
+                .cloned();
+            let (path, watch_dir, mask) = match &parent {
+                Some(parent) => {
+                    let dir = self.inner.get(parent).unwrap();
+                    // Normalized paths with a parent always have a file name
+                    let name = dumped.path.file_name().ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Directory path {} has no name", dumped.path.display()),
+                        )
+                    })?;
+                    (PathBuf::from(name), dir.watch_dir.clone(), dir.mask)
+                }
+                None => match watch_dirs
+                    .iter()
+                    .find(|watch_dir| Path::new(&watch_dir.path) == dumped.path)
+                {
+                    Some(watch_dir) => (
+                        dumped.path.clone(),
+                        watch_dir.clone(),
+                        watch_dir.watch_events.unwrap_or(self.mask),
+                    ),
+                    // Not a root, the parent was left out or failed to be watched. As the paths
+                    // are normalized, this leaves out everything outside of the WatchDirs
+                    None => continue,
+                },
+            };
+
+            let desc = match watch(&mut inotify.watches(), &watch_dir, &dumped.path, mask) {
+                Ok(desc) => desc,
+                Err(why) => {
+                    log::error!(
+                        "Failed to create listener for directory {}, skipping: {}",
+                        dumped.path.display(),
+                        why
+                    );
+                    continue;
+                }
+            };
+
+            if let Some(parent) = &parent {
+                self.inner.get_mut(parent).unwrap().children.insert(
+                    path.clone(),
+                    ChildIndex::Directory {
+                        descriptor: Some(desc.clone()),
+                    },
+                );
+            }
+
+            self.inner.insert(
+                desc.clone(),
+                DirectoryIndex {
+                    path,
+                    children: dumped
+                        .children
+                        .into_iter()
+                        .map(|(path, child)| {
+                            let child = match child {
+                                DumpedChild::File => ChildIndex::File {},
+                                DumpedChild::Symlink(target) => ChildIndex::Symlink { target },
+                                // Watched directories get their descriptor once they are loaded
+                                DumpedChild::Directory => {
+                                    ChildIndex::Directory { descriptor: None }
+                                }
+                            };
+                            (path, child)
+                        })
+                        .collect(),
+                    watch_dir,
+                    parent,
+                    mask,
+                },
+            );
+            descriptors.insert(dumped.path, desc);
+        }
+
+        self.rebuild_extensions();
+
+        log::info!("Index loaded! Indexed roots: {:?}", self.top_level_dirs());
 
         Ok(())
     }
@@ -352,11 +1721,102 @@ impl Index {
     pub fn clear_index(&mut self, inotify: &mut Inotify) -> io::Result<()> {
         // Remove every watcher
         for descriptor in self.inner.keys() {
-            inotify.rm_watch(descriptor.clone())?;
+            unwatch(inotify, descriptor)?;
         }
         // Clear the inner index after it has been cleaned up
         self.inner.clear();
+        self.reset_changes();
+        self.extensions.clear();
 
         Ok(())
     }
 }
+
+/// The `limit` largest of the files, largest first. Sizes are read from the filesystem, so the
+/// index should not be locked meanwhile
+pub fn largest_files(files: Vec<IndexEntry>, limit: usize) -> Vec<IndexEntry> {
+    // Min-heap of the largest files found so far, by their position in `files`
+    let mut heap = BinaryHeap::with_capacity(limit + 1);
+    for (i, entry) in files.iter().enumerate() {
+        let len = match fs::metadata(&entry.path) {
+            Ok(metadata) => metadata.len(),
+            Err(_) => continue,
+        };
+        heap.push(Reverse((len, i)));
+        if heap.len() > limit {
+            heap.pop();
+        }
+    }
+
+    let mut files = files.into_iter().map(Some).collect::<Vec<_>>();
+    heap.into_sorted_vec()
+        .into_iter()
+        .filter_map(|Reverse((_, i))| files[i].take())
+        .collect()
+}
+
+/// The least recently modified of the paths. Modification times are read from the filesystem,
+/// so the index should not be locked meanwhile
+pub fn oldest_modified(paths: Vec<PathBuf>) -> Option<PathBuf> {
+    paths
+        .into_iter()
+        .filter_map(|path| {
+            let modified = fs::metadata(&path).and_then(|m| m.modified()).ok()?;
+            Some((modified, path))
+        })
+        .min_by_key(|(modified, _)| *modified)
+        .map(|(_, path)| path)
+}
+
+/// Levels `path` is nested below its WatchDir root, 1 for direct children
+fn depth_below(path: &Path, watch_dir_root: Option<&Path>) -> usize {
+    watch_dir_root
+        .and_then(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path)
+        .components()
+        .count()
+}
+
+/// Watch a directory of a WatchDir, only numbering it if the WatchDir is polled instead
+fn watch(
+    watches: &mut Watches,
+    watch_dir: &WatchDir,
+    path: &Path,
+    mask: WatchMask,
+) -> io::Result<Descriptor> {
+    if watch_dir.polling_interval.is_some() {
+        Ok(Descriptor::Polled(
+            NEXT_POLLED.fetch_add(1, Ordering::Relaxed),
+        ))
+    } else {
+        watches.add(path, mask).map(Descriptor::Watch)
+    }
+}
+
+/// Remove the inotify watcher of an indexed directory, polled directories have none
+fn unwatch(inotify: &mut Inotify, desc: &Descriptor) -> io::Result<()> {
+    match desc.watch() {
+        Some(wd) => inotify.watches().remove(wd.clone()),
+        None => Ok(()),
+    }
+}
+
+/// The entry of an indexed child at its full path
+fn child_entry(path: PathBuf, child: &ChildIndex, watch_dir_root: Option<PathBuf>) -> IndexEntry {
+    IndexEntry {
+        depth: depth_below(&path, watch_dir_root.as_deref()),
+        path,
+        directory: matches!(child, ChildIndex::Directory { .. }),
+        symlink: matches!(child, ChildIndex::Symlink { .. }),
+        symlink_target: match child {
+            ChildIndex::Symlink { target } => Some(target.clone()),
+            _ => None,
+        },
+        watch_dir_root,
+    }
+}
+
+/// The key of a path in the extension index
+fn extension_key(path: &Path) -> Option<String> {
+    Some(path.extension()?.to_string_lossy().to_lowercase())
+}
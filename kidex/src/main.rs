@@ -1,33 +1,105 @@
-use std::{collections::HashMap, env, fs, io, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    convert::identity,
+    env, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
 
+use event_log::EventLog;
 use futures::StreamExt;
 use globber::Pattern;
-use index::{GetPath, Index};
-use inotify::{EventMask, Inotify, WatchDescriptor};
-use kidex_common::{IndexEntry, IpcCommand, IpcResponse, DEFAULT_SOCKET};
+use index::{largest_files, oldest_modified, GetPath, Index};
+use inotify::{EventMask, Inotify, WatchDescriptor, WatchMask};
+use kidex_common::{
+    ChangeEvent, ConfigPatch, EventKind, HealthInfo, IndexChange, IndexStatistics, IpcCommand,
+    IpcReply, IpcRequest, IpcResponse, PatternsInfo, ReloadResult, TreeNode, WatchDirInfo,
+    WatchDirStatistics, DEFAULT_SOCKET,
+};
+use rate_limit::TokenBucket;
 use serde::{de::Error, Deserialize, Deserializer};
-use signal_hook::consts::TERM_SIGNALS;
+use signal_hook::consts::{SIGUSR1, SIGUSR2, TERM_SIGNALS};
 use signal_hook_tokio::Signals;
 use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufStream},
-    net::UnixListener,
+    net::{UnixListener, UnixStream},
     sync::{
+        broadcast,
         mpsc::{self, Receiver, Sender},
-        Mutex,
+        oneshot, Mutex,
     },
+    task::JoinSet,
 };
 
+mod config;
+mod event_log;
 mod index;
+mod rate_limit;
 
 #[derive(Deserialize)]
 pub struct Config {
     directories: Vec<WatchDir>,
     #[serde(deserialize_with = "parse_pattern_vec")]
-    ignored: Vec<Pattern>,
+    ignored: Vec<IgnorePattern>,
+    /// Match ignore patterns case sensitively, unless overridden by a WatchDir
+    #[serde(default = "default_true")]
+    case_sensitive_ignore: bool,
+    /// Amount of threads used to crawl WatchDirs during a full index
+    #[serde(default)]
+    concurrent_index_workers: Option<usize>,
+    /// Limit the rate of IPC requests per client process
+    #[serde(default)]
+    enable_rate_limiting: bool,
+    /// Only accept shutdown and index load requests from processes of the user running the daemon
+    #[serde(default = "default_true")]
+    restrict_admin_commands: bool,
+    /// Accept commands faking index changes, for testing integrations. Not meant for production
+    #[serde(default)]
+    allow_simulate_commands: bool,
+    /// Append every file system event to this file as a JSON line
+    #[serde(default)]
+    watch_events_log: Option<PathBuf>,
+    /// Rotate the event log once it grows past this many megabytes
+    #[serde(default)]
+    watch_events_log_max_mb: Option<u64>,
+    /// Wait this long before reading the config on a reload, merging the reloads
+    /// requested in the meantime
+    #[serde(default = "default_reload_debounce_ms")]
+    reload_debounce_ms: u64,
+}
+
+/// An ignore pattern along with the string it was parsed from
+#[derive(Clone, Debug)]
+pub struct IgnorePattern {
+    source: String,
+    pattern: Pattern,
+}
+
+impl IgnorePattern {
+    fn new(source: &str) -> Result<Self, String> {
+        Ok(Self {
+            source: source.to_string(),
+            pattern: Pattern::new(source).map_err(|why| why.to_string())?,
+        })
+    }
+
+    fn matches(&self, string: &str) -> bool {
+        self.pattern.matches(string)
+    }
+
+    /// The same pattern with all letters in lowercase, for case insensitive matching
+    fn to_lowercase(&self) -> Self {
+        // Lowercasing does not affect the glob syntax, so the pattern stays valid
+        Self::new(&self.source.to_lowercase()).unwrap_or_else(|_| self.clone())
+    }
 }
 
 /// Custom parser to handle the patterns
-fn parse_pattern_vec<'de, D>(deserializer: D) -> Result<Vec<Pattern>, D::Error>
+fn parse_pattern_vec<'de, D>(deserializer: D) -> Result<Vec<IgnorePattern>, D::Error>
 where
     D: Deserializer<'de>,
 {
@@ -35,7 +107,7 @@ where
     let mut final_vec = Vec::new();
 
     for string in vec {
-        final_vec.push(match Pattern::new(&string) {
+        final_vec.push(match IgnorePattern::new(&string) {
             Ok(pattern) => pattern,
             Err(why) => {
                 return Err(D::Error::custom(why));
@@ -46,6 +118,62 @@ where
     Ok(final_vec)
 }
 
+fn default_true() -> bool {
+    true
+}
+
+fn default_reload_debounce_ms() -> u64 {
+    500
+}
+
+/// Custom parser to handle polling intervals given in seconds
+fn parse_interval<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Option::<u64>::deserialize(deserializer)?.map(Duration::from_secs))
+}
+
+/// Custom parser to handle the optional list of watched events
+fn parse_watch_mask<'de, D>(deserializer: D) -> Result<Option<WatchMask>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<Vec<String>>::deserialize(deserializer)? {
+        Some(events) => watch_mask_from_names(&events)
+            .map(Some)
+            .map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+/// Combine inotify event names like `"CREATE"` or `"CLOSE_WRITE"` into a single mask
+fn watch_mask_from_names(names: &[String]) -> Result<WatchMask, String> {
+    let mut mask = WatchMask::empty();
+
+    for name in names {
+        mask |= match name.as_str() {
+            "ACCESS" => WatchMask::ACCESS,
+            "ATTRIB" => WatchMask::ATTRIB,
+            "CLOSE" => WatchMask::CLOSE,
+            "CLOSE_NOWRITE" => WatchMask::CLOSE_NOWRITE,
+            "CLOSE_WRITE" => WatchMask::CLOSE_WRITE,
+            "CREATE" => WatchMask::CREATE,
+            "DELETE" => WatchMask::DELETE,
+            "DELETE_SELF" => WatchMask::DELETE_SELF,
+            "MODIFY" => WatchMask::MODIFY,
+            "MOVE" => WatchMask::MOVE,
+            "MOVE_SELF" => WatchMask::MOVE_SELF,
+            "MOVED_FROM" => WatchMask::MOVED_FROM,
+            "MOVED_TO" => WatchMask::MOVED_TO,
+            "OPEN" => WatchMask::OPEN,
+            _ => return Err(format!("Unknown watch event: {}", name)),
+        };
+    }
+
+    Ok(mask)
+}
+
 /// Describes a directory that is watched for changes
 #[derive(Clone, Debug, Deserialize)]
 pub struct WatchDir {
@@ -53,9 +181,93 @@ pub struct WatchDir {
     path: String,
     /// Ignored patterns
     #[serde(deserialize_with = "parse_pattern_vec")]
-    ignored: Vec<Pattern>,
+    ignored: Vec<IgnorePattern>,
+    /// Match ignore patterns case sensitively, `None` uses the global setting
+    #[serde(default)]
+    case_sensitive_ignore: Option<bool>,
     /// Recursively watch directories
     recurse: bool,
+    /// Inotify events to watch for, defaults to creations, deletions and moves
+    #[serde(default, deserialize_with = "parse_watch_mask")]
+    watch_events: Option<WatchMask>,
+    /// Periodically recrawl the directory, for filesystems where inotify
+    /// does not report all changes (e.g. network filesystems)
+    #[serde(default, deserialize_with = "parse_interval")]
+    polling_interval: Option<Duration>,
+    /// Files smaller than this many bytes are not indexed, `None` means no limit
+    #[serde(default)]
+    min_file_size: Option<u64>,
+    /// Files larger than this many bytes are not indexed, `None` means no limit
+    #[serde(default)]
+    max_file_size: Option<u64>,
+    /// Index directories starting with a `.`
+    #[serde(default = "default_true")]
+    watch_dot_dirs: bool,
+    /// Names of directories to skip, without needing glob syntax
+    #[serde(default)]
+    ignored_dirs: Vec<String>,
+    /// Directories with names matching these patterns are indexed, but not watched or recursed into
+    #[serde(default, deserialize_with = "parse_pattern_vec")]
+    recurse_ignore_patterns: Vec<IgnorePattern>,
+    /// `ignored_dirs` compiled for lookups during indexing
+    #[serde(skip)]
+    ignored_dir_set: HashSet<String>,
+}
+
+impl WatchDir {
+    /// Check if a path matches any of the ignore patterns
+    pub fn is_ignored(&self, path: &str) -> bool {
+        if self.case_sensitive_ignore == Some(false) {
+            let path = path.to_lowercase();
+            self.ignored.iter().any(|pat| pat.matches(&path))
+        } else {
+            self.ignored.iter().any(|pat| pat.matches(path))
+        }
+    }
+
+    /// Check if a subdirectory with the given name should be watched and recursed into
+    pub fn recurses_into(&self, name: &Path) -> bool {
+        if !self.recurse {
+            return false;
+        }
+
+        let name = name.to_string_lossy();
+        if self.case_sensitive_ignore == Some(false) {
+            let name = name.to_lowercase();
+            !self
+                .recurse_ignore_patterns
+                .iter()
+                .any(|pat| pat.matches(&name))
+        } else {
+            !self
+                .recurse_ignore_patterns
+                .iter()
+                .any(|pat| pat.matches(&name))
+        }
+    }
+
+    /// Check if a file of the given size falls within the configured limits
+    pub fn size_allowed(&self, len: u64) -> bool {
+        self.min_file_size.is_none_or(|min| len >= min)
+            && self.max_file_size.is_none_or(|max| len <= max)
+    }
+
+    /// Describe the WatchDir for clients
+    pub fn info(&self) -> WatchDirInfo {
+        WatchDirInfo {
+            path: PathBuf::from(&self.path),
+            recurse: self.recurse,
+            ignored: self.ignored.iter().map(|pat| pat.source.clone()).collect(),
+            ignored_dirs: self.ignored_dirs.clone(),
+            polling_interval_secs: self.polling_interval.map(|interval| interval.as_secs()),
+        }
+    }
+
+    /// Check if a directory with the given name should be left out of the index
+    pub fn skips_dir(&self, name: &Path) -> bool {
+        let name = name.to_string_lossy();
+        (!self.watch_dot_dirs && name.starts_with('.')) || self.ignored_dir_set.contains(&*name)
+    }
 }
 
 /// A "top-level" object representing a directory being watched, and keeping track of it's children
@@ -66,30 +278,130 @@ pub struct DirectoryIndex {
     /// Reference to the underlying `WatchDir` object containing some
     /// configuration details
     watch_dir: Arc<WatchDir>,
-    parent: Option<WatchDescriptor>,
+    parent: Option<Descriptor>,
+    /// The mask used for the watcher of this directory
+    mask: WatchMask,
+}
+
+/// The key of an indexed directory
+#[derive(Debug, Clone, Hash, Eq, PartialEq)]
+pub enum Descriptor {
+    /// The inotify watcher of the directory
+    Watch(WatchDescriptor),
+    /// Directories of polled `WatchDir`s have no watcher, they are numbered instead
+    Polled(u64),
+}
+
+impl Descriptor {
+    /// The inotify watcher, `None` for polled directories
+    pub fn watch(&self) -> Option<&WatchDescriptor> {
+        match self {
+            Descriptor::Watch(wd) => Some(wd),
+            Descriptor::Polled(_) => None,
+        }
+    }
+}
+
+impl DirectoryIndex {
+    /// Amount of files in this directory and all of its subdirectories
+    pub fn file_count(&self, index: &Index) -> usize {
+        self.count_children(index, |child| matches!(child, ChildIndex::File {}))
+    }
+
+    /// Amount of directories in this directory and all of its subdirectories
+    pub fn dir_count(&self, index: &Index) -> usize {
+        self.count_children(index, |child| matches!(child, ChildIndex::Directory { .. }))
+    }
+
+    /// Whether this directory is the root of a `WatchDir` rather than a subdirectory of one
+    pub fn is_root(&self) -> bool {
+        self.parent.is_none()
+    }
+
+    /// Amount of levels between this directory and the root of its `WatchDir`
+    pub fn depth(&self, index: &Index) -> usize {
+        let mut depth = 0;
+        let mut parent = self.parent.as_ref();
+        while let Some(dir) = parent.and_then(|desc| index.inner.get(desc)) {
+            depth += 1;
+            parent = dir.parent.as_ref();
+        }
+        depth
+    }
+
+    fn count_children(&self, index: &Index, filter: fn(&ChildIndex) -> bool) -> usize {
+        let count =
+            |dir: &DirectoryIndex| dir.children.values().filter(|child| filter(child)).count();
+
+        count(self)
+            + self
+                .children
+                .values()
+                .filter_map(|child| match child {
+                    ChildIndex::Directory {
+                        descriptor: Some(descriptor),
+                    } => Some(descriptor.clone()),
+                    _ => None,
+                })
+                .flat_map(|descriptor| index.traverse(descriptor).into_values())
+                .map(|dir| count(&dir))
+                .sum::<usize>()
+    }
 }
 
 /// A child of an indexed directory
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub enum ChildIndex {
     File {},
-    Directory { descriptor: Option<WatchDescriptor> },
+    Directory {
+        descriptor: Option<Descriptor>,
+    },
+    /// Symlinks are not followed, whatever they point to is left out of the index
+    Symlink {
+        target: PathBuf,
+    },
 }
 
 /// Sent from the IPC listener to the main event loop
 #[derive(Debug)]
 enum EventLoopMsg {
-    FullIndex,
+    /// Reindex everything, replying with the result
+    FullIndex(mpsc::UnboundedSender<IpcResponse>),
     Quit,
-    Reload,
+    /// Reload the config, skipping the debounce if forced
+    Reload(bool, oneshot::Sender<IpcResponse>),
+    /// Change individual config fields, replying with the result
+    SetConfig(ConfigPatch, oneshot::Sender<IpcResponse>),
+    /// Collect diagnostics, replying with the result
+    HealthCheck(oneshot::Sender<IpcResponse>),
+    /// Check for stale watchers, replying with the result
+    Validate(oneshot::Sender<IpcResponse>),
+    /// Get the sources of the global ignore patterns in the config
+    ConfigPatterns(oneshot::Sender<Vec<String>>),
+    /// Remove entries missing from the filesystem, replying with the result
+    PruneStale(oneshot::Sender<IpcResponse>),
+    CleanOrphans(oneshot::Sender<IpcResponse>),
+    /// Replace the index with a dumped one, replying with the result
+    LoadIndex(Vec<u8>, oneshot::Sender<IpcResponse>),
+    /// Change the watched events of a directory, replying with the result
+    SetWatchMask(PathBuf, Vec<String>, oneshot::Sender<IpcResponse>),
+    UpdateIgnoredFor(PathBuf, Vec<String>, oneshot::Sender<IpcResponse>),
+    /// Apply a fake `Created` or `Deleted` change to the index
+    Simulate(EventKind, PathBuf, oneshot::Sender<IpcResponse>),
+    ForceRewatch(PathBuf, oneshot::Sender<IpcResponse>),
 }
 
+/// How long to wait for the IPC task to send its last replies when quitting
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
 #[tokio::main]
 async fn main() {
     env_logger::init();
 
-    let config_path = format!(
-        "{}/.config/kidex.ron",
+    let started = Instant::now();
+
+    let config_path = Config::find(&PathBuf::from(format!(
+        "{}/.config",
         match env::var("HOME") {
             Ok(home) => home,
             Err(why) => {
@@ -97,16 +409,42 @@ async fn main() {
                 return;
             }
         }
-    );
+    )));
     let mut inotify = Inotify::init().expect("Failed to init inotify");
-    let mut config: Config = ron::from_str(&fs::read_to_string(&config_path).unwrap()).unwrap();
+    let mut config = match Config::load(&config_path) {
+        Ok(config) => config,
+        Err(why) => {
+            log::error!("Failed to load config {}: {}", config_path.display(), why);
+            return;
+        }
+    };
     let mut index = Index::new();
 
     index
         .full_index(&mut inotify, &config)
         .expect("Failed to complete initial index!");
 
+    // Past 90% the full index already warns
+    if let Some(max) = max_user_watches() {
+        let current = index.inner.len();
+        if current * 10 >= max * 8 && current * 10 < max * 9 {
+            log::warn!(
+                "Using {} of the {} available inotify watchers, consider raising fs.inotify.max_user_watches",
+                current,
+                max
+            );
+        }
+    }
+
+    warn_simulate_commands(&config);
+
     let index = Arc::new(Mutex::new(index));
+    // Set while a requested full index is pending or running
+    let indexing = Arc::new(AtomicBool::new(false));
+    let rate_limiting = Arc::new(AtomicBool::new(config.enable_rate_limiting));
+    let restrict_admin = Arc::new(AtomicBool::new(config.restrict_admin_commands));
+    // Set by a graceful shutdown, refusing any further requests
+    let shutting_down = Arc::new(AtomicBool::new(false));
 
     let socket_path = env::var("SOCKET_PATH").unwrap_or(DEFAULT_SOCKET.to_string());
     // Delete the socket file if it is lingering around
@@ -114,51 +452,296 @@ async fn main() {
 
     let listener = UnixListener::bind(&socket_path).expect("Failed to create unix socket listener");
 
+    let events_socket = events_socket_path(Path::new(&socket_path));
+    let _ = fs::remove_file(&events_socket);
+    let events_listener =
+        UnixListener::bind(&events_socket).expect("Failed to create event socket listener");
+
     // Create necessary communication channels
     let (ipc_tx, mut events_rx) = mpsc::channel::<EventLoopMsg>(32);
     let (events_tx, ipc_rx) = mpsc::channel::<()>(32);
+    let (change_tx, _) = broadcast::channel::<ChangeEvent>(256);
 
     // Spawn task listening for termination signals
     tokio::spawn(signal_task(
         ipc_tx.clone(),
-        Signals::new(TERM_SIGNALS).unwrap(),
+        indexing.clone(),
+        shutting_down.clone(),
+        Signals::new(TERM_SIGNALS.iter().chain(&[SIGUSR1, SIGUSR2])).unwrap(),
     ));
     // Spawn IPC task
-    tokio::spawn(ipc_task(listener, index.clone(), ipc_tx, ipc_rx));
+    let ipc_handle = tokio::spawn(ipc_task(
+        listener,
+        index.clone(),
+        indexing.clone(),
+        rate_limiting.clone(),
+        restrict_admin.clone(),
+        shutting_down,
+        events_socket,
+        ipc_tx,
+        ipc_rx,
+    ));
+    // Spawn task streaming changes to subscribers
+    tokio::spawn(subscriber_task(events_listener, change_tx.clone()));
 
     // Buffer used by inotify
     let mut buffer = [0; 1024];
+    // When each polled WatchDir was last recrawled
+    let mut last_polled = HashMap::<String, Instant>::new();
+    let mut event_log = open_event_log(&config);
+    // Set after a full index, validating the watchers when the next event arrives
+    let mut validate_pending = true;
+    // Messages received while debouncing a reload, handled before any new ones
+    let mut deferred = VecDeque::<EventLoopMsg>::new();
 
     'event_loop: loop {
         // Sleep for a while to not keep the thread busy all the time
         tokio::time::sleep(Duration::from_millis(10)).await;
-        match events_rx.try_recv() {
+        match deferred
+            .pop_front()
+            .map_or_else(|| events_rx.try_recv(), Ok)
+        {
             Ok(event) => match event {
-                EventLoopMsg::FullIndex => {
-                    index
+                EventLoopMsg::FullIndex(reply_tx) => {
+                    let warnings = index
                         .lock()
                         .await
-                        .full_index(&mut inotify, &config)
+                        .full_index_with_progress(&mut inotify, &config, &|current, total| {
+                            let _ = reply_tx.send(IpcResponse::Progress {
+                                current,
+                                total: Some(total),
+                            });
+                        })
                         .unwrap();
+                    indexing.store(false, Ordering::SeqCst);
+                    validate_pending = true;
+                    let _ = reply_tx.send(if warnings.is_empty() {
+                        IpcResponse::Success
+                    } else {
+                        IpcResponse::Warning(warnings)
+                    });
                 }
                 EventLoopMsg::Quit => break,
-                EventLoopMsg::Reload => {
-                    match serde_json::from_str::<Config>(&fs::read_to_string(&config_path).unwrap())
-                    {
+                EventLoopMsg::Reload(force, reply_tx) => {
+                    if force {
+                        log::debug!("Forced config reload, skipping the debounce");
+                    } else {
+                        log::debug!(
+                            "Debouncing config reload for {}ms",
+                            config.reload_debounce_ms
+                        );
+                        // Give whatever is writing the config time to finish
+                        tokio::time::sleep(Duration::from_millis(config.reload_debounce_ms)).await;
+                    }
+
+                    // Reloads requested while waiting get the result of this one
+                    let mut reply_txs = vec![reply_tx];
+                    while let Ok(event) = events_rx.try_recv() {
+                        match event {
+                            EventLoopMsg::Reload(_, reply_tx) => reply_txs.push(reply_tx),
+                            event => deferred.push_back(event),
+                        }
+                    }
+
+                    log::info!("Reloading config, requested {} time(s)", reply_txs.len());
+                    let result = match Config::load(&config_path) {
                         Ok(new_config) => {
+                            let result = compare_directories(&config, &new_config);
                             config = new_config;
+                            last_polled.clear();
+                            rate_limiting.store(config.enable_rate_limiting, Ordering::SeqCst);
+                            restrict_admin.store(config.restrict_admin_commands, Ordering::SeqCst);
+                            warn_simulate_commands(&config);
+                            event_log = open_event_log(&config);
                             // Reindex everything if the config was reloaded
-                            index
+                            let warnings = index
                                 .lock()
                                 .await
                                 .full_index(&mut inotify, &config)
                                 .unwrap();
+                            validate_pending = true;
+                            Ok(ReloadResult { warnings, ..result })
                         }
                         Err(why) => {
                             log::error!("Failed to load config: {}", why);
+                            Err(format!("Failed to load config: {}", why))
                         }
+                    };
+                    for reply_tx in reply_txs {
+                        let _ = reply_tx.send(match &result {
+                            Ok(result) => IpcResponse::ReloadResult(result.clone()),
+                            Err(why) => IpcResponse::Error(why.clone()),
+                        });
                     }
                 }
+                EventLoopMsg::SetConfig(patch, reply_tx) => {
+                    let response = match apply_patch(&mut config, patch) {
+                        Ok(reindex) => {
+                            // Only changes affecting what is indexed require a reindex
+                            if reindex {
+                                index
+                                    .lock()
+                                    .await
+                                    .full_index(&mut inotify, &config)
+                                    .unwrap();
+                                validate_pending = true;
+                            }
+                            IpcResponse::Success
+                        }
+                        Err(why) => IpcResponse::Error(why),
+                    };
+                    let _ = reply_tx.send(response);
+                }
+                EventLoopMsg::SetWatchMask(path, events, reply_tx) => {
+                    let response = match watch_mask_from_names(&events) {
+                        Ok(mask) => {
+                            if index.lock().await.set_watch_mask(&mut inotify, &path, mask) {
+                                // Keep the mask for later reindexes of a root
+                                if let Some(watch_dir) = config
+                                    .directories
+                                    .iter_mut()
+                                    .find(|watch_dir| Path::new(&watch_dir.path) == path)
+                                {
+                                    watch_dir.watch_events = Some(mask);
+                                }
+                                IpcResponse::Success
+                            } else {
+                                IpcResponse::NotFound
+                            }
+                        }
+                        Err(why) => IpcResponse::Error(why),
+                    };
+                    let _ = reply_tx.send(response);
+                }
+                EventLoopMsg::UpdateIgnoredFor(path, patterns, reply_tx) => {
+                    let parsed = patterns
+                        .iter()
+                        .map(|string| IgnorePattern::new(string))
+                        .collect::<Result<Vec<_>, _>>();
+                    let watch_dir = config
+                        .directories
+                        .iter_mut()
+                        .find(|watch_dir| Path::new(&watch_dir.path) == path);
+
+                    let response = match (parsed, watch_dir) {
+                        (Err(why), _) => IpcResponse::Error(why),
+                        (Ok(_), None) => IpcResponse::NotFound,
+                        (Ok(parsed), Some(watch_dir)) => {
+                            let mut index = index.lock().await;
+                            // Configured roots that failed to index are left untouched
+                            if index.top_level_dirs().contains(&path) {
+                                // Kept in the config so that later reindexes of the root use them too
+                                watch_dir.ignored = parsed;
+                                match index.update_watch_dir(&mut inotify, &config, &path) {
+                                    Ok(()) => IpcResponse::Success,
+                                    Err(why) => {
+                                        IpcResponse::Error(format!("Failed to reindex: {}", why))
+                                    }
+                                }
+                            } else {
+                                IpcResponse::NotFound
+                            }
+                        }
+                    };
+                    let _ = reply_tx.send(response);
+                }
+                EventLoopMsg::ForceRewatch(path, reply_tx) => {
+                    let response = match index.lock().await.rewatch(&mut inotify, &path) {
+                        Ok(()) => IpcResponse::Success,
+                        Err(why) if why.kind() == io::ErrorKind::NotFound => IpcResponse::NotFound,
+                        Err(why) => {
+                            log::error!("Failed to rewatch {}: {}", path.display(), why);
+                            IpcResponse::Error(format!("Failed to add watcher: {}", why))
+                        }
+                    };
+                    let _ = reply_tx.send(response);
+                }
+                EventLoopMsg::Simulate(kind, path, reply_tx) => {
+                    let response = if config.allow_simulate_commands {
+                        let mut index = index.lock().await;
+                        let result = if kind == EventKind::Created {
+                            index.add_entry(&mut inotify, &path)
+                        } else {
+                            index.remove_entry(&mut inotify, &path)
+                        };
+
+                        match result {
+                            Ok(()) => {
+                                log::info!("Simulated {:?} event for {}", kind, path.display());
+                                // Sending only fails when nobody is subscribed
+                                let _ = change_tx.send(ChangeEvent { kind, path });
+                                IpcResponse::Success
+                            }
+                            Err(why) if why.kind() == io::ErrorKind::NotFound => {
+                                IpcResponse::NotFound
+                            }
+                            Err(why) => IpcResponse::Error(why.to_string()),
+                        }
+                    } else {
+                        IpcResponse::Error(
+                            "Simulate commands are disabled, enable allow_simulate_commands in the config"
+                                .to_string(),
+                        )
+                    };
+                    let _ = reply_tx.send(response);
+                }
+                EventLoopMsg::ConfigPatterns(reply_tx) => {
+                    let _ = reply_tx.send(
+                        config
+                            .ignored
+                            .iter()
+                            .map(|pat| pat.source.clone())
+                            .collect(),
+                    );
+                }
+                EventLoopMsg::PruneStale(reply_tx) => {
+                    let pruned = index.lock().await.rebuild_stale(&mut inotify);
+                    log::info!("Pruned {} stale entries", pruned);
+                    let _ = reply_tx.send(IpcResponse::Pruned(pruned));
+                }
+                EventLoopMsg::CleanOrphans(reply_tx) => {
+                    let orphans = index.lock().await.clean_orphans(&mut inotify);
+                    log::info!("Removed {} orphaned directories", orphans.len());
+                    let _ = reply_tx.send(IpcResponse::OrphanedPaths(orphans));
+                }
+                EventLoopMsg::LoadIndex(blob, reply_tx) => {
+                    let response = match index.lock().await.load(&mut inotify, &config, &blob) {
+                        Ok(()) => IpcResponse::Success,
+                        Err(why) => {
+                            log::error!("Failed to load index: {}", why);
+                            IpcResponse::Error(format!("Failed to load index: {}", why))
+                        }
+                    };
+                    let _ = reply_tx.send(response);
+                }
+                EventLoopMsg::Validate(reply_tx) => {
+                    let stale = index.lock().await.validate_watchers(&mut inotify);
+                    let _ = reply_tx.send(IpcResponse::StalePaths(stale));
+                }
+                EventLoopMsg::HealthCheck(reply_tx) => {
+                    let index = index.lock().await;
+                    let children = index.inner.values().flat_map(|dir| dir.children.values());
+
+                    let _ = reply_tx.send(IpcResponse::Health(HealthInfo {
+                        version: env!("CARGO_PKG_VERSION").to_string(),
+                        uptime_secs: started.elapsed().as_secs(),
+                        watcher_count: index.inner.len(),
+                        max_watchers: max_user_watches(),
+                        file_count: children
+                            .clone()
+                            .filter(|child| matches!(child, ChildIndex::File {}))
+                            .count(),
+                        dir_count: children
+                            .filter(|child| matches!(child, ChildIndex::Directory { .. }))
+                            .count(),
+                        inaccessible_dirs: config
+                            .directories
+                            .iter()
+                            .map(|dir| PathBuf::from(&dir.path))
+                            .filter(|path| !path.exists())
+                            .collect(),
+                    }));
+                }
             },
             Err(mpsc::error::TryRecvError::Empty) => (),
             Err(why) => {
@@ -166,6 +749,38 @@ async fn main() {
             }
         }
 
+        for watch_dir in &config.directories {
+            if let Some(interval) = watch_dir.polling_interval {
+                let last = last_polled
+                    .entry(watch_dir.path.clone())
+                    .or_insert_with(Instant::now);
+
+                if last.elapsed() >= interval {
+                    log::debug!("Polling WatchDir {}", watch_dir.path);
+                    *last = Instant::now();
+                    if let Err(why) = index
+                        .lock()
+                        .await
+                        .reindex_subtree(&mut inotify, Path::new(&watch_dir.path))
+                    {
+                        log::error!("Failed to poll WatchDir {}: {}", watch_dir.path, why);
+                    }
+                }
+            }
+        }
+
+        if let Some(event_log) = &mut event_log {
+            if let Err(why) = event_log.flush_if_due() {
+                log::error!("Failed to write event log: {}", why);
+            }
+        }
+
+        // Validated before reading, so that the events read are never for watchers it drops
+        if validate_pending {
+            index.lock().await.validate_watchers(&mut inotify);
+            validate_pending = false;
+        }
+
         let events = match inotify.read_events(&mut buffer) {
             Ok(events) => events,
             // The next event(s) is/are not ready yet if the error is WouldBlock
@@ -180,141 +795,1130 @@ async fn main() {
 
         for event in events {
             let mut index = index.lock().await;
+            let desc = Descriptor::Watch(event.wd.clone());
 
-            if index.inner.get(&event.wd).is_none() {
+            // Removing a watcher queues an IN_IGNORED event for it, which must not drop the rest
+            if !index.inner.contains_key(&desc) {
                 log::warn!("Event received from nonexistent watcher: {:?}", event.name);
-                continue 'event_loop;
+                continue;
             }
 
             let path = if let Some(name) = event.name {
                 PathBuf::from(name)
             } else {
                 log::warn!("Event received with no name!");
-                continue 'event_loop;
+                continue;
             };
 
             let path_str = format!(
                 "{}/{}",
-                index.inner.get_path(&event.wd).display(),
+                index.inner.get_path(&desc).display(),
                 path.display()
             );
 
+            let mut report = |kind| {
+                if let Some(event_log) = &mut event_log {
+                    if let Err(why) = event_log.log(kind, &path_str) {
+                        log::error!("Failed to write event log: {}", why);
+                    }
+                }
+                // Sending only fails when nobody is subscribed
+                let _ = change_tx.send(ChangeEvent {
+                    kind,
+                    path: PathBuf::from(&path_str),
+                });
+            };
+
             if event.mask.contains(EventMask::CREATE) {
                 log::info!("File created: {}", path_str);
-                index.create_index(&mut inotify, &path, &event);
+                report(EventKind::Created);
+                index.create_index(&mut inotify, &desc, &path);
             }
             if event.mask.contains(EventMask::DELETE) {
                 log::info!("File deleted: {}", path_str);
-                index.remove_index(&mut inotify, &path, &event);
+                report(EventKind::Deleted);
+                index.remove_index(&mut inotify, &desc, &path, event.mask);
             }
             if event.mask.contains(EventMask::MOVED_FROM) {
                 log::info!("File moved from: {}", path_str);
-                index.remove_index(&mut inotify, &path, &event);
+                report(EventKind::MovedFrom);
+                index.remove_index(&mut inotify, &desc, &path, event.mask);
             }
             if event.mask.contains(EventMask::MOVED_TO) {
                 log::info!("File moved to: {}", path_str);
-                index.create_index(&mut inotify, &path, &event);
+                report(EventKind::MovedTo);
+                index.create_index(&mut inotify, &desc, &path);
             }
         }
     }
 
+    // Answer the requests that will not be handled, the IPC task waits for their replies.
+    // Requests sent after closing get an error from the IPC task itself
+    events_rx.close();
+    while let Some(event) = deferred.pop_front().or_else(|| events_rx.try_recv().ok()) {
+        refuse_shutting_down(event);
+    }
+
     index.lock().await.clear_index(&mut inotify).unwrap();
 
-    events_tx.send(()).await.unwrap();
+    // The IPC task is already gone if it was told to quit directly
+    let _ = events_tx.send(()).await;
+    // Let the IPC task finish sending the replies still in flight
+    match tokio::time::timeout(SHUTDOWN_TIMEOUT, ipc_handle).await {
+        Ok(Ok(())) => (),
+        Ok(Err(why)) => log::error!("IPC task failed: {}", why),
+        Err(_) => log::warn!("Timed out waiting for the IPC task, quitting anyway"),
+    }
+}
+
+/// Answer a request the event loop will no longer get to
+fn refuse_shutting_down(event: EventLoopMsg) {
+    let response = IpcResponse::Error("The daemon is shutting down".to_string());
+    match event {
+        EventLoopMsg::FullIndex(reply_tx) => {
+            let _ = reply_tx.send(response);
+        }
+        EventLoopMsg::Reload(_, reply_tx)
+        | EventLoopMsg::SetConfig(_, reply_tx)
+        | EventLoopMsg::HealthCheck(reply_tx)
+        | EventLoopMsg::Validate(reply_tx)
+        | EventLoopMsg::PruneStale(reply_tx)
+        | EventLoopMsg::CleanOrphans(reply_tx)
+        | EventLoopMsg::LoadIndex(_, reply_tx)
+        | EventLoopMsg::SetWatchMask(_, _, reply_tx)
+        | EventLoopMsg::UpdateIgnoredFor(_, _, reply_tx)
+        | EventLoopMsg::Simulate(_, _, reply_tx)
+        | EventLoopMsg::ForceRewatch(_, reply_tx) => {
+            let _ = reply_tx.send(response);
+        }
+        // Dropping the sender is answered with the same error
+        EventLoopMsg::ConfigPatterns(_) | EventLoopMsg::Quit => (),
+    }
+}
+
+/// Simulated changes let any client fake the contents of the index
+fn warn_simulate_commands(config: &Config) {
+    if config.allow_simulate_commands {
+        log::warn!("Simulate commands are enabled, this should not be used in production");
+    }
+}
+
+/// Open the event log if one is configured
+fn open_event_log(config: &Config) -> Option<EventLog> {
+    let path = config.watch_events_log.as_ref()?;
+
+    match EventLog::open(path, config.watch_events_log_max_mb) {
+        Ok(event_log) => Some(event_log),
+        Err(why) => {
+            log::error!("Failed to open event log {}: {}", path.display(), why);
+            None
+        }
+    }
+}
+
+/// Check if a path matches any of the ignore patterns added at runtime
+fn is_runtime_ignored(runtime_ignored: &[(String, Pattern)], path: &Path) -> bool {
+    runtime_ignored
+        .iter()
+        .any(|(_, pat)| pat.matches(&path.to_string_lossy()))
+}
+
+/// Send a request to the event loop and write its answer from a separate task, so that clients
+/// are not blocked by requests the event loop is slow to get to
+fn reply_from_event_loop<T: Send + 'static>(
+    replies: &mut JoinSet<()>,
+    ipc_tx: &Sender<EventLoopMsg>,
+    mut stream: BufStream<UnixStream>,
+    id: Option<u64>,
+    request: impl FnOnce(oneshot::Sender<T>) -> EventLoopMsg,
+    respond: impl FnOnce(T) -> IpcResponse + Send + 'static,
+) {
+    let (reply_tx, reply_rx) = oneshot::channel();
+    let request = request(reply_tx);
+    let ipc_tx = ipc_tx.clone();
+
+    replies.spawn(async move {
+        // Either fails only when the event loop has quit without getting to the request
+        let response = match ipc_tx.send(request).await {
+            Ok(()) => match reply_rx.await {
+                Ok(reply) => respond(reply),
+                Err(_) => IpcResponse::Error("The daemon is shutting down".to_string()),
+            },
+            Err(_) => IpcResponse::Error("The daemon is shutting down".to_string()),
+        };
+        if let Err(why) = stream
+            .write_all(&encode_reply(id, &response).unwrap())
+            .await
+        {
+            log::error!("Error writing reply to stream: {}", why);
+        }
+        let _ = stream.flush().await;
+    });
+}
+
+/// Serialize a response, wrapped in an `IpcReply` if the request had an ID
+fn encode_reply(id: Option<u64>, response: &IpcResponse) -> serde_json::Result<Vec<u8>> {
+    match id {
+        Some(id) => serde_json::to_vec(&IpcReply { id, response }),
+        None => serde_json::to_vec(response),
+    }
+}
+
+/// Read the system limit for inotify watchers
+fn max_user_watches() -> Option<usize> {
+    fs::read_to_string("/proc/sys/fs/inotify/max_user_watches")
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
 }
 
-async fn signal_task(signal_tx: Sender<EventLoopMsg>, mut signals: Signals) {
-    // Wait for a signal to arrive, we only listen for termination signals so any
-    // received event will be one we should act on
-    signals.next().await;
+/// Apply the set fields of a patch to the config, returning whether a reindex is needed
+fn apply_patch(config: &mut Config, patch: ConfigPatch) -> Result<bool, String> {
+    let mut reindex = false;
 
-    log::info!("Termination signal received! Quitting...");
+    if let Some(ignored) = patch.ignored {
+        config.ignored = ignored
+            .iter()
+            .map(|string| IgnorePattern::new(string))
+            .collect::<Result<_, _>>()?;
+        reindex = true;
+    }
+    if let Some(workers) = patch.concurrent_index_workers {
+        config.concurrent_index_workers = Some(workers);
+    }
 
-    signal_tx.send(EventLoopMsg::Quit).await.unwrap();
+    Ok(reindex)
 }
 
+/// Find out which watched directories a config reload adds and removes
+fn compare_directories(old: &Config, new: &Config) -> ReloadResult {
+    let old_dirs = old
+        .directories
+        .iter()
+        .map(|dir| PathBuf::from(&dir.path))
+        .collect::<Vec<_>>();
+    let new_dirs = new
+        .directories
+        .iter()
+        .map(|dir| PathBuf::from(&dir.path))
+        .collect::<Vec<_>>();
+
+    ReloadResult {
+        added_dirs: new_dirs
+            .iter()
+            .filter(|dir| !old_dirs.contains(dir))
+            .cloned()
+            .collect(),
+        removed_dirs: old_dirs
+            .iter()
+            .filter(|dir| !new_dirs.contains(dir))
+            .cloned()
+            .collect(),
+        unchanged_dirs: new_dirs
+            .iter()
+            .filter(|dir| old_dirs.contains(dir))
+            .cloned()
+            .collect(),
+        warnings: Vec::new(),
+    }
+}
+
+/// The event socket lives next to the command socket, `kidex.sock` becoming `kidex-events.sock`
+fn events_socket_path(socket_path: &Path) -> PathBuf {
+    let stem = socket_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    socket_path.with_file_name(format!("{}-events.sock", stem))
+}
+
+/// Accept subscribers on the event socket, streaming every change to them
+async fn subscriber_task(listener: UnixListener, change_tx: broadcast::Sender<ChangeEvent>) {
+    loop {
+        let mut stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(why) => {
+                log::error!("Failed to accept subscriber: {}", why);
+                continue;
+            }
+        };
+        let mut change_rx = change_tx.subscribe();
+
+        tokio::spawn(async move {
+            loop {
+                let event = match change_rx.recv().await {
+                    Ok(event) => event,
+                    Err(broadcast::error::RecvError::Lagged(count)) => {
+                        log::warn!("Subscriber lagged behind, dropped {} events", count);
+                        // Tell the subscriber to resync and continue from the newest change
+                        change_rx = change_rx.resubscribe();
+                        ChangeEvent {
+                            kind: EventKind::Lagged,
+                            path: PathBuf::new(),
+                        }
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+
+                let mut buf = serde_json::to_vec(&event).unwrap();
+                buf.push(0x0);
+
+                // The subscriber disconnected
+                if stream.write_all(&buf).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+async fn signal_task(
+    signal_tx: Sender<EventLoopMsg>,
+    indexing: Arc<AtomicBool>,
+    shutting_down: Arc<AtomicBool>,
+    mut signals: Signals,
+) {
+    while let Some(signal) = signals.next().await {
+        match signal {
+            SIGUSR1 | SIGUSR2 if shutting_down.load(Ordering::SeqCst) => {
+                log::info!(
+                    "Signal {} received, but the daemon is shutting down",
+                    signal
+                );
+            }
+            SIGUSR1 => {
+                if indexing.swap(true, Ordering::SeqCst) {
+                    log::info!("SIGUSR1 received, but a reindex is already in progress");
+                    continue;
+                }
+                log::info!("SIGUSR1 received! Reindexing...");
+                // Nobody is waiting for the reply
+                let (reply_tx, _) = mpsc::unbounded_channel();
+                signal_tx
+                    .send(EventLoopMsg::FullIndex(reply_tx))
+                    .await
+                    .unwrap();
+            }
+            SIGUSR2 => {
+                log::info!("SIGUSR2 received! Reloading config...");
+                let (reply_tx, _) = oneshot::channel();
+                signal_tx
+                    .send(EventLoopMsg::Reload(false, reply_tx))
+                    .await
+                    .unwrap();
+            }
+            // Every other signal we listen for is a termination signal
+            _ => {
+                log::info!("Termination signal received! Quitting...");
+                signal_tx.send(EventLoopMsg::Quit).await.unwrap();
+                break;
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 async fn ipc_task(
     listener: UnixListener,
     index: Arc<Mutex<Index>>,
+    indexing: Arc<AtomicBool>,
+    rate_limiting: Arc<AtomicBool>,
+    restrict_admin: Arc<AtomicBool>,
+    shutting_down: Arc<AtomicBool>,
+    events_socket: PathBuf,
     ipc_tx: Sender<EventLoopMsg>,
     mut ipc_rx: Receiver<()>,
 ) {
+    // Tasks sending the replies of running reindexes and reloads
+    let mut replies = JoinSet::new();
+
+    // Rate limits of the connecting clients, by process ID
+    let mut buckets: HashMap<i32, TokenBucket> = HashMap::new();
+
+    // Patterns added at runtime, hiding matching entries from the results
+    let mut runtime_ignored: Vec<(String, Pattern)> = Vec::new();
+
+    // SAFETY: getuid has no preconditions and cannot fail
+    let uid = unsafe { libc::getuid() };
+
     loop {
         tokio::select! {
             Ok((stream, _)) = listener.accept() => {
+                let mut limited = false;
+                if rate_limiting.load(Ordering::SeqCst) {
+                    if let Some(pid) = stream.peer_cred().ok().and_then(|cred| cred.pid()) {
+                        // Forget about clients that have been idle for a while
+                        if buckets.len() > 1024 {
+                            buckets.retain(|_, bucket| !bucket.is_full());
+                        }
+
+                        if !buckets.entry(pid).or_insert_with(TokenBucket::new).try_acquire() {
+                            log::warn!("Rate limit exceeded by process {}", pid);
+                            limited = true;
+                        }
+                    }
+                }
+
+                let peer_uid = stream.peer_cred().ok().map(|cred| cred.uid());
+
                 let mut buf = Vec::new();
                 let mut stream = BufStream::new(stream);
                 stream.read_until(0x0, &mut buf).await.unwrap();
                 buf.pop(); // Remove the delimiting null byte
 
-                match serde_json::from_slice::<IpcCommand>(&buf).unwrap() {
+                // Commands are sent either bare or wrapped in an `IpcRequest`
+                let (id, command) = match serde_json::from_slice::<IpcRequest>(&buf) {
+                    Ok(request) => (Some(request.id), request.command),
+                    Err(_) => match serde_json::from_slice::<IpcCommand>(&buf) {
+                        Ok(command) => (None, command),
+                        Err(why) => {
+                            log::warn!("Received a malformed request: {}", why);
+                            let response = IpcResponse::Error("malformed request".to_string());
+                            if let Err(why) = stream.write_all(&encode_reply(None, &response).unwrap()).await {
+                                log::error!("Error writing reply to stream: {}", why);
+                            }
+                            let _ = stream.flush().await;
+                            continue;
+                        }
+                    },
+                };
+
+                let refusal = if limited {
+                    Some("Rate limit exceeded")
+                } else if shutting_down.load(Ordering::SeqCst) {
+                    Some("The daemon is shutting down")
+                } else if matches!(
+                    command,
+                    IpcCommand::Quit | IpcCommand::GracefulShutdown | IpcCommand::LoadIndex(_)
+                ) && restrict_admin.load(Ordering::SeqCst)
+                    && peer_uid != Some(uid)
+                {
+                    log::warn!("Refused admin command from user {:?}", peer_uid);
+                    Some("Permission denied")
+                } else {
+                    None
+                };
+                if let Some(refusal) = refusal {
+                    let response = IpcResponse::Error(refusal.to_string());
+                    if let Err(why) = stream.write_all(&encode_reply(id, &response).unwrap()).await {
+                        log::error!("Error writing reply to stream: {}", why);
+                    }
+                    stream.flush().await.unwrap();
+                    continue;
+                }
+
+                // Let clients correlate slow replies with their requests right away
+                if let (Some(id), IpcCommand::FullIndex | IpcCommand::Reload | IpcCommand::ForceReload) = (id, &command) {
+                    let mut buf = encode_reply(Some(id), &IpcResponse::Ack).unwrap();
+                    buf.push(0x0);
+                    if let Err(why) = stream.write_all(&buf).await {
+                        log::error!("Error writing reply to stream: {}", why);
+                    }
+                    stream.flush().await.unwrap();
+                }
+
+                match command {
                     IpcCommand::FullIndex => {
-                        ipc_tx.send(EventLoopMsg::FullIndex).await.unwrap();
-                        if let Err(why) = stream.write_all(&serde_json::to_vec(&IpcResponse::Success).unwrap()).await {
-                            log::error!("Error writing reply to stream: {}", why);
+                        if indexing.swap(true, Ordering::SeqCst) {
+                            if let Err(why) = stream.write_all(&encode_reply(id, &IpcResponse::Busy).unwrap()).await {
+                                log::error!("Error writing reply to stream: {}", why);
+                            }
+                        } else {
+                            let (reply_tx, mut reply_rx) = mpsc::unbounded_channel();
+                            ipc_tx.send(EventLoopMsg::FullIndex(reply_tx)).await.unwrap();
+
+                            // Reply once the index is done without blocking other clients,
+                            // sending the progress as null-delimited frames before the result
+                            replies.spawn(async move {
+                                while let Some(response) = reply_rx.recv().await {
+                                    let mut buf = encode_reply(id, &response).unwrap();
+                                    if matches!(response, IpcResponse::Progress { .. }) {
+                                        buf.push(0x0);
+                                    }
+                                    if let Err(why) = stream.write_all(&buf).await {
+                                        log::error!("Error writing reply to stream: {}", why);
+                                        break;
+                                    }
+                                    stream.flush().await.unwrap();
+                                }
+                            });
+                            continue;
                         }
                     }
                     IpcCommand::Quit => {
                         ipc_tx.send(EventLoopMsg::Quit).await.unwrap();
-                        if let Err(why) = stream.write_all(&serde_json::to_vec(&IpcResponse::Success).unwrap()).await {
+                        if let Err(why) = stream.write_all(&encode_reply(id, &IpcResponse::Success).unwrap()).await {
                             log::error!("Error writing reply to stream: {}", why);
                         }
+                        stream.flush().await.unwrap();
                         break;
                     }
-                    IpcCommand::Reload => {
-                        ipc_tx.send(EventLoopMsg::Reload).await.unwrap();
-                        if let Err(why) = stream.write_all(&serde_json::to_vec(&IpcResponse::Success).unwrap()).await {
+                    IpcCommand::GracefulShutdown => {
+                        // Keep serving until the event loop has finished what it is doing
+                        shutting_down.store(true, Ordering::SeqCst);
+                        ipc_tx.send(EventLoopMsg::Quit).await.unwrap();
+                        if let Err(why) = stream.write_all(&encode_reply(id, &IpcResponse::Success).unwrap()).await {
                             log::error!("Error writing reply to stream: {}", why);
                         }
                     }
+                    IpcCommand::Reload | IpcCommand::ForceReload => {
+                        let force = matches!(command, IpcCommand::ForceReload);
+                        // Other clients' reloads get merged into this one meanwhile
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            |reply_tx| EventLoopMsg::Reload(force, reply_tx),
+                            identity,
+                        );
+                        continue;
+                    }
                     IpcCommand::GetIndex(path) => {
                         let index = index.lock().await;
                         let paths = match path {
-                            Some(path) => {
-                                index
-                                    .inner
-                                    .iter()
-                                    .find(|(_, dir)| dir.path == path)
-                                    .map(|(desc, _)| index.traverse(desc.clone())
-                                        .into_iter()
-                                        .flat_map(|(desc, dir)| {
-                                            let parent_path = index.inner.get_path(&desc);
-                                            dir.children.into_iter().map(move |(path, child)|
-                                                IndexEntry {
-                                                    path: parent_path.iter().chain(path.iter()).collect(),
-                                                    directory: matches!(child, ChildIndex::Directory {..})
-                                                }
-                                            )
+                            Some(path) => index
+                                .descriptor_for_path(&path)
+                                .map(|desc| index.iter_dir(desc).collect::<Vec<_>>()),
+                            None => Some(index.iter().collect()),
+                        }
+                        .map(|paths| {
+                            paths
+                                .into_iter()
+                                .filter(|entry| {
+                                    !is_runtime_ignored(&runtime_ignored, &entry.path)
+                                })
+                                .collect::<Vec<_>>()
+                        });
+
+                        let buf = encode_reply(id, &match paths {
+                            Some(paths) => IpcResponse::Index(paths),
+                            None => IpcResponse::NotFound,
+                        }).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    },
+                    IpcCommand::QueryIndexExact(path) => {
+                        let response = match index.lock().await.find_by_path(&path) {
+                            // Runtime ignored entries are hidden like in `GetIndex`
+                            Some(entry)
+                                if !is_runtime_ignored(&runtime_ignored, &entry.path) =>
+                            {
+                                IpcResponse::Entry(entry)
+                            }
+                            _ => IpcResponse::NotFound,
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetIndexCount(path) => {
+                        let index = index.lock().await;
+                        let desc = match &path {
+                            Some(path) => index.descriptor_for_path(path).map(Some),
+                            None => Some(None),
+                        };
+                        let response = match desc {
+                            // Runtime patterns match against paths, so they have to be resolved
+                            Some(desc) if !runtime_ignored.is_empty() => {
+                                let entries: Box<dyn Iterator<Item = _>> = match desc {
+                                    Some(desc) => Box::new(index.iter_dir(desc)),
+                                    None => Box::new(index.iter()),
+                                };
+                                IpcResponse::Count(
+                                    entries
+                                        .filter(|entry| {
+                                            !is_runtime_ignored(&runtime_ignored, &entry.path)
                                         })
-                                        .collect::<Vec<_>>()
-                                    )
+                                        .count(),
+                                )
                             }
-                            None => Some(index
+                            Some(desc) => IpcResponse::Count(index.entry_count(desc)),
+                            None => IpcResponse::NotFound,
+                        };
+                        drop(index);
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetIndexFiltered(filter) => {
+                        let index = index.lock().await;
+                        let entries = match &filter.root_path {
+                            Some(path) => index
+                                .descriptor_for_path(path)
+                                .map(|desc| index.iter_dir(desc).collect::<Vec<_>>()),
+                            None => Some(index.iter().collect()),
+                        }
+                        .map(|entries| {
+                            entries
+                                .into_iter()
+                                .filter(|entry| {
+                                    filter.matches(entry)
+                                        && !is_runtime_ignored(&runtime_ignored, &entry.path)
+                                })
+                                .collect::<Vec<_>>()
+                        });
+
+                        let buf = encode_reply(id, &match entries {
+                            Some(entries) => IpcResponse::Index(entries),
+                            None => IpcResponse::NotFound,
+                        }).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::Stats => {
+                        let index = index.lock().await;
+                        let buf = encode_reply(id, &IpcResponse::Stats(IndexStatistics {
+                            watched_dirs: index.inner.len(),
+                            indexed_entries: index.inner.values().map(|dir| dir.children.len()).sum(),
+                            memory_usage_estimate: index.memory_usage_estimate(),
+                            watch_dirs: index
                                 .inner
-                                .iter()
-                                .flat_map(|(desc, dir)| {
-                                    let parent_path = index.inner.get_path(desc);
-                                    dir.children.iter().map(move |(path, child)|
-                                        IndexEntry {
-                                            path: parent_path.clone().iter().chain(path.iter()).collect(),
-                                            directory: matches!(child, ChildIndex::Directory {..}),
-                                        })
+                                .values()
+                                .filter(|dir| dir.is_root())
+                                .map(|dir| WatchDirStatistics {
+                                    path: dir.path.clone(),
+                                    file_count: dir.file_count(&index),
+                                    dir_count: dir.dir_count(&index),
                                 })
-                                .collect()
-                            )
+                                .collect(),
+                        })).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::AddIgnorePattern(string) => {
+                        let response = match Pattern::new(&string) {
+                            Ok(pattern) => {
+                                runtime_ignored.push((string, pattern));
+                                // Synced copies of the index have to be rebuilt
+                                index.lock().await.reset_changes();
+                                IpcResponse::Success
+                            }
+                            Err(why) => IpcResponse::Error(why.to_string()),
                         };
 
-                        let buf = serde_json::to_vec(&match paths {
-                            Some(paths) => IpcResponse::Index(paths),
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::RemoveIgnorePattern(string) => {
+                        let len = runtime_ignored.len();
+                        runtime_ignored.retain(|(pat_string, _)| *pat_string != string);
+
+                        let response = if runtime_ignored.len() < len {
+                            index.lock().await.reset_changes();
+                            IpcResponse::Success
+                        } else {
+                            IpcResponse::NotFound
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::TestIgnorePattern(pattern, path) => {
+                        let response = match Pattern::new(&pattern) {
+                            Ok(pat) => IpcResponse::TestResult {
+                                matches: pat.matches(&path.to_string_lossy()),
+                                pattern_debug: format!("{:?}", pat),
+                            },
+                            Err(why) => IpcResponse::Error(format!("Invalid pattern: {}", why)),
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::ListIgnorePatterns => {
+                        let runtime_patterns = runtime_ignored
+                            .iter()
+                            .map(|(string, _)| string.clone())
+                            .collect();
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            EventLoopMsg::ConfigPatterns,
+                            |config_patterns| {
+                                IpcResponse::Patterns(PatternsInfo {
+                                    config_patterns,
+                                    runtime_patterns,
+                                })
+                            },
+                        );
+                        continue;
+                    }
+                    IpcCommand::GetRecentlyDeleted { limit } => {
+                        let index = index.lock().await;
+                        let buf = encode_reply(id, &IpcResponse::RecentlyDeleted(
+                            index
+                                .recently_deleted()
+                                .filter(|entry| {
+                                    !is_runtime_ignored(&runtime_ignored, &entry.path)
+                                })
+                                .take(limit)
+                                .collect(),
+                        )).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::GetRecentlyCreated { limit } => {
+                        let index = index.lock().await;
+                        let buf = encode_reply(id, &IpcResponse::RecentFiles(
+                            index
+                                .recently_created()
+                                .filter(|entry| {
+                                    !is_runtime_ignored(&runtime_ignored, &entry.path)
+                                })
+                                .take(limit)
+                                .collect(),
+                        )).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::GetIndexSince(generation) => {
+                        let index = index.lock().await;
+                        let is_ignored = |path: &Path| {
+                            is_runtime_ignored(&runtime_ignored, path)
+                        };
+                        let response = match index.changes_since(generation) {
+                            Some(changes) => IpcResponse::IndexDiff {
+                                current_generation: index.generation(),
+                                changes: changes
+                                    .into_iter()
+                                    .filter(|change| match change {
+                                        IndexChange::Added(entry) => !is_ignored(&entry.path),
+                                        IndexChange::Removed(_) => true,
+                                    })
+                                    .collect(),
+                            },
+                            None => IpcResponse::IndexSnapshot {
+                                current_generation: index.generation(),
+                                entries: index.iter().filter(|entry| !is_ignored(&entry.path)).collect(),
+                            },
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetWatchDirStats(path) => {
+                        let stats = index.lock().await.watch_dir_stats(&path);
+                        let response = match stats {
+                            Some((mut stats, entries)) => {
+                                stats.oldest_indexed_entry = oldest_modified(entries);
+                                IpcResponse::WatchDirStats(stats)
+                            }
+                            None => IpcResponse::NotFound,
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetIndexByExtension(ext) => {
+                        let entries = index
+                            .lock()
+                            .await
+                            .by_extension(&ext)
+                            .into_iter()
+                            .filter(|entry| {
+                                !is_runtime_ignored(&runtime_ignored, &entry.path)
+                            })
+                            .collect();
+                        let buf = encode_reply(id, &IpcResponse::Index(entries)).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::GetIndexDepth(depth, root) => {
+                        let entries = index.lock().await.at_depth(depth, root.as_deref());
+                        let response = match entries {
+                            Some(entries) => IpcResponse::Index(
+                                entries
+                                    .into_iter()
+                                    .filter(|entry| {
+                                        !is_runtime_ignored(&runtime_ignored, &entry.path)
+                                    })
+                                    .collect(),
+                            ),
+                            None => IpcResponse::NotFound,
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetDuplicateNames(name) => {
+                        let entries = index
+                            .lock()
+                            .await
+                            .find_by_name(&name)
+                            .into_iter()
+                            .filter(|entry| {
+                                !is_runtime_ignored(&runtime_ignored, &entry.path)
+                            })
+                            .collect();
+                        let buf = encode_reply(id, &IpcResponse::Index(entries)).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::GetSymlinks(root) => {
+                        let index = index.lock().await;
+                        let symlinks = match &root {
+                            Some(root) => index
+                                .descriptor_for_path(root)
+                                .map(|desc| index.symlinks(Some(desc))),
+                            None => Some(index.symlinks(None)),
+                        };
+                        drop(index);
+
+                        let response = match symlinks {
+                            Some(symlinks) => IpcResponse::Index(
+                                symlinks
+                                    .into_iter()
+                                    .filter(|entry| {
+                                        !is_runtime_ignored(&runtime_ignored, &entry.path)
+                                    })
+                                    .collect(),
+                            ),
+                            None => IpcResponse::NotFound,
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetEmptyDirs(root) => {
+                        let index = index.lock().await;
+                        let dirs = match &root {
+                            Some(root) => index
+                                .descriptor_for_path(root)
+                                .map(|desc| index.empty_dirs(Some(desc))),
+                            None => Some(index.empty_dirs(None)),
+                        };
+                        drop(index);
+
+                        let response = match dirs {
+                            Some(dirs) => IpcResponse::Index(
+                                dirs.into_iter()
+                                    .filter(|entry| {
+                                        !is_runtime_ignored(&runtime_ignored, &entry.path)
+                                    })
+                                    .collect(),
+                            ),
+                            None => IpcResponse::NotFound,
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetIndexLargestFiles { limit, root } => {
+                        let index = index.lock().await;
+                        let keep = |path: &Path| {
+                            !is_runtime_ignored(&runtime_ignored, path)
+                        };
+
+                        let files = match &root {
+                            Some(root) => index
+                                .descriptor_for_path(root)
+                                .map(|desc| index.files(Some(desc), keep)),
+                            None => Some(index.files(None, keep)),
+                        };
+                        drop(index);
+
+                        // Stat the files without holding up the index
+                        let response = match files {
+                            Some(files) => IpcResponse::Index(largest_files(files, limit)),
+                            None => IpcResponse::NotFound,
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::QueryIndexFuzzy(query, limit) => {
+                        let entries = index.lock().await.fuzzy_search(&query, limit, |path| {
+                            !is_runtime_ignored(&runtime_ignored, path)
+                        });
+                        let buf = encode_reply(id, &IpcResponse::Index(entries)).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::ListExtensions(root) => {
+                        let index = index.lock().await;
+                        let keep = |path: &Path| {
+                            !is_runtime_ignored(&runtime_ignored, path)
+                        };
+
+                        let response = match &root {
+                            Some(root) if index.descriptor_for_path(root).is_none() => {
+                                IpcResponse::NotFound
+                            }
+                            root => IpcResponse::Extensions(
+                                index.extension_counts(root.as_deref(), keep),
+                            ),
+                        };
+                        drop(index);
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetIndexAsTree(path) => {
+                        let index = index.lock().await;
+                        let keep = |path: &Path| {
+                            !is_runtime_ignored(&runtime_ignored, path)
+                        };
+
+                        let tree = match path {
+                            Some(path) => index.descriptor_for_path(&path).map(|desc| {
+                                index.tree(&desc, path.to_string_lossy().into_owned(), &keep)
+                            }),
+                            // Group the roots under a nameless node
+                            None => {
+                                let mut roots = index
+                                    .inner
+                                    .iter()
+                                    .filter(|(_, dir)| dir.is_root())
+                                    .map(|(desc, dir)| {
+                                        index.tree(desc, dir.path.to_string_lossy().into_owned(), &keep)
+                                    })
+                                    .collect::<Vec<_>>();
+                                roots.sort_by(|a, b| a.name.cmp(&b.name));
+                                Some(TreeNode {
+                                    name: String::new(),
+                                    is_dir: true,
+                                    children: roots,
+                                })
+                            }
+                        };
+
+                        let buf = encode_reply(id, &match tree {
+                            Some(tree) => IpcResponse::Tree(tree),
                             None => IpcResponse::NotFound,
                         }).unwrap();
 
                         stream.write_all(&buf).await.unwrap();
-                    },
+                    }
+                    IpcCommand::GetParentChain(path) => {
+                        let response = match index.lock().await.parent_chain(&path) {
+                            Some(chain) => IpcResponse::ParentChain(chain),
+                            None => IpcResponse::NotFound,
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetWatchDirForPath(path) => {
+                        let info = index
+                            .lock()
+                            .await
+                            .watch_dir_for_path(&path)
+                            .map(|watch_dir| watch_dir.info());
+                        let buf = encode_reply(id, &IpcResponse::WatchDirForPath(info)).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::GetRoots => {
+                        let buf = encode_reply(id, &IpcResponse::Roots(
+                            index.lock().await.top_level_dirs(),
+                        )).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::SetConfig(patch) => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            |reply_tx| EventLoopMsg::SetConfig(patch, reply_tx),
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::SetWatchMask(path, events) => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            |reply_tx| EventLoopMsg::SetWatchMask(path, events, reply_tx),
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::UpdateIgnoredFor(path, patterns) => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            |reply_tx| EventLoopMsg::UpdateIgnoredFor(path, patterns, reply_tx),
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::ForceRewatch(path) => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            |reply_tx| EventLoopMsg::ForceRewatch(path, reply_tx),
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::SimulateCreate(path) => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            |reply_tx| EventLoopMsg::Simulate(EventKind::Created, path, reply_tx),
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::SimulateDelete(path) => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            |reply_tx| EventLoopMsg::Simulate(EventKind::Deleted, path, reply_tx),
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::HealthCheck => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            EventLoopMsg::HealthCheck,
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::DumpIndex => {
+                        let response = match index.lock().await.dump() {
+                            Ok(blob) => IpcResponse::Blob(blob),
+                            Err(why) => IpcResponse::Error(format!("Failed to dump index: {}", why)),
+                        };
+                        let buf = encode_reply(id, &response).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::LoadIndex(blob) => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            |reply_tx| EventLoopMsg::LoadIndex(blob, reply_tx),
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::Subscribe => {
+                        let buf = encode_reply(id, &IpcResponse::SubscribeAck {
+                            events_socket: events_socket.clone(),
+                        }).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::PruneStale => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            EventLoopMsg::PruneStale,
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::GetOrphanedDescriptors => {
+                        let orphans = index.lock().await.orphaned_paths();
+                        let buf = encode_reply(id, &IpcResponse::OrphanedPaths(orphans)).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::CleanOrphans => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            EventLoopMsg::CleanOrphans,
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::Validate => {
+                        reply_from_event_loop(
+                            &mut replies,
+                            &ipc_tx,
+                            stream,
+                            id,
+                            EventLoopMsg::Validate,
+                            identity,
+                        );
+                        continue;
+                    }
+                    IpcCommand::MovePath { from, to } => {
+                        let response = match index.lock().await.rename_entry(&from, &to) {
+                            Ok(()) => IpcResponse::Success,
+                            Err(why) if why.kind() == io::ErrorKind::NotFound => IpcResponse::NotFound,
+                            Err(why) => IpcResponse::Error(format!("Failed to move path: {}", why)),
+                        };
+                        let buf = encode_reply(id, &response).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::GetIndexForDescriptor(descriptor) => {
+                        let index = index.lock().await;
+                        let response = match index.descriptor_for_id(descriptor) {
+                            Some(desc) => {
+                                let dir = &index.inner[&desc];
+                                IpcResponse::DirectoryInfo {
+                                    path: index.inner.get_path(&desc),
+                                    child_count: dir.children.len(),
+                                    children: index.dir_entries(&desc, dir).collect(),
+                                    parent_path: dir
+                                        .parent
+                                        .as_ref()
+                                        .map(|parent| index.inner.get_path(parent)),
+                                }
+                            }
+                            None => IpcResponse::NotFound,
+                        };
+                        drop(index);
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetDescriptorCount => {
+                        let current = index.lock().await.inner.len();
+                        let response = match max_user_watches() {
+                            Some(max) => IpcResponse::DescriptorCount {
+                                current,
+                                max,
+                                warning: (current * 10 > max * 9).then(|| {
+                                    format!(
+                                        "Using {} of the {} available inotify watchers",
+                                        current, max
+                                    )
+                                }),
+                            },
+                            None => IpcResponse::Error(
+                                "Failed to read the inotify watcher limit".to_string(),
+                            ),
+                        };
+
+                        stream.write_all(&encode_reply(id, &response).unwrap()).await.unwrap();
+                    }
+                    IpcCommand::GetPid => {
+                        let buf = encode_reply(id, &IpcResponse::Pid(std::process::id())).unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
+                    IpcCommand::GetVersion => {
+                        let buf = encode_reply(id, &IpcResponse::Version(
+                            env!("CARGO_PKG_VERSION").to_string(),
+                        ))
+                        .unwrap();
+
+                        stream.write_all(&buf).await.unwrap();
+                    }
                 }
                 stream.flush().await.unwrap();
             }
             _ = ipc_rx.recv() => break
         }
     }
+
+    while replies.join_next().await.is_some() {}
 }
@@ -0,0 +1,46 @@
+use std::time::Instant;
+
+/// Amount of requests a client can make in a burst
+const BURST: f64 = 100.0;
+/// Amount of requests per second a client can sustain
+const RATE: f64 = 50.0;
+
+/// Limits the rate of requests from a single client
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    pub fn new() -> Self {
+        Self {
+            tokens: BURST,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take a token for a request, returning false if the client is over the limit
+    pub fn try_acquire(&mut self) -> bool {
+        self.refill();
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Check if the bucket has refilled completely, meaning the client has been idle
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= BURST
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens =
+            (self.tokens + now.duration_since(self.last_refill).as_secs_f64() * RATE).min(BURST);
+        self.last_refill = now;
+    }
+}